@@ -0,0 +1,82 @@
+use crate::instruction::OperandKind;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AssemblerErrorKind {
+  ParseError,
+  UnknownOpcode,
+  UnknownSymbol,
+  DuplicateLabel,
+  BadOperandCount,
+  UnexpectedToken,
+  ImmediateOutOfRange,
+  RegisterOutOfRange,
+  WrongOperandType { expected: OperandKind },
+  UnknownDirective,
+  UnknownSection,
+  MissingLabel,
+  UnexpectedLabel,
+  DivisionByZero,
+  ExpressionOverflow,
+  EntryPointNotCode,
+}
+
+impl fmt::Display for AssemblerErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let message = match self {
+      AssemblerErrorKind::ParseError => "unable to parse",
+      AssemblerErrorKind::UnknownOpcode => "unknown opcode",
+      AssemblerErrorKind::UnknownSymbol => "unknown symbol",
+      AssemblerErrorKind::DuplicateLabel => "duplicate label",
+      AssemblerErrorKind::BadOperandCount => "wrong number of operands",
+      AssemblerErrorKind::UnexpectedToken => "unexpected token",
+      AssemblerErrorKind::ImmediateOutOfRange => "immediate value out of range",
+      AssemblerErrorKind::RegisterOutOfRange => "register out of range",
+      AssemblerErrorKind::WrongOperandType { expected } => {
+        return write!(f, "expected {} operand", expected);
+      }
+      AssemblerErrorKind::UnknownDirective => "unknown directive",
+      AssemblerErrorKind::UnknownSection => "unknown section",
+      AssemblerErrorKind::MissingLabel => "missing label",
+      AssemblerErrorKind::UnexpectedLabel => "directive cannot take a label",
+      AssemblerErrorKind::DivisionByZero => "division by zero in expression",
+      AssemblerErrorKind::ExpressionOverflow => "expression overflows",
+      AssemblerErrorKind::EntryPointNotCode => "entry point must be a code label",
+    };
+    write!(f, "{}", message)
+  }
+}
+
+/// Diagnostic pointing at the offending text in the assembler source. Lines
+/// and columns start at 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssemblerError {
+  pub kind: AssemblerErrorKind,
+  pub line: usize,
+  pub column: usize,
+  pub text: String,
+}
+
+impl AssemblerError {
+  pub fn new(kind: AssemblerErrorKind, line: usize, column: usize, text: &str) -> AssemblerError {
+    AssemblerError {
+      kind,
+      line,
+      column,
+      text: text.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for AssemblerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}:{}: {} `{}`",
+      self.line, self.column, self.kind, self.text
+    )
+  }
+}
+
+impl Error for AssemblerError {}
@@ -1,16 +1,17 @@
+use super::expression_parser::expression;
 use super::instruction_parser::AsmInstruction;
-use super::label_parser::label_declaration;
-use super::operand_parser::operand;
+use super::label_parser::{identifier, label_declaration};
+use super::operand_parser::{operand, with_source};
 use super::Token;
-use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::{alpha1, multispace, space1};
 
 named!(directive_declaration<CompleteStr, Token>,
   do_parse!(
     tag!(".") >>
     name: alpha1 >>
     (
-      Token::Directive{name: name.to_string()}
+      Token::Directive{name: name.to_lowercase()}
     )
   )
 );
@@ -20,19 +21,49 @@ named!(directive_combined<CompleteStr, AsmInstruction>,
     do_parse!(
       l: opt!(label_declaration) >>
       name: directive_declaration >>
-      o1: opt!(operand) >>
-      o2: opt!(operand) >>
-      o3: opt!(operand) >>
+      o1: opt!(call!(with_source, operand)) >>
+      o2: opt!(call!(with_source, operand)) >>
+      o3: opt!(call!(with_source, operand)) >>
       (
-        AsmInstruction::new(Some(name), l, None, o1, o2, o3)
+        AsmInstruction::parsed(Some(name), l, None, vec![o1, o2, o3])
       )
     )
   )
 );
 
+// `.equ NAME value` and `.set NAME value`, where the value is an expression
+// with an optional `#`. The constant is kept as the label of the directive,
+// so `NAME: .equ #value` is the same. The name and value must follow on the
+// directive's own line.
+named!(constant_directive<CompleteStr, AsmInstruction>,
+  do_parse!(
+    opt!(multispace) >>
+    tag!(".") >>
+    name: alt!(tag_no_case!("equ") | tag_no_case!("set")) >>
+    space1 >>
+    symbol: identifier >>
+    space1 >>
+    value: call!(with_source, constant_value) >>
+    opt!(multispace) >>
+    (
+      AsmInstruction::parsed(
+        Some(Token::Directive { name: name.to_lowercase() }),
+        Some(Token::LabelDeclaration { name: symbol.to_string() }),
+        None,
+        vec![Some(value)],
+      )
+    )
+  )
+);
+
+named!(constant_value<CompleteStr, Token>,
+  map!(preceded!(opt!(tag!("#")), expression), |expr| Token::IntegerExpression { expr })
+);
+
 named!(pub directive<CompleteStr, AsmInstruction>,
   do_parse!(
     ins: alt!(
+      constant_directive |
       directive_combined
     ) >>
     (
@@ -51,7 +82,7 @@ mod tests {
     assert!(result.is_ok());
     let (_, directive) = result.unwrap();
 
-    let correct_instruction = AsmInstruction::new(
+    let correct_instruction = AsmInstruction::parsed(
       Some(Token::Directive {
         name: "asciiz".to_string(),
       }),
@@ -59,13 +90,37 @@ mod tests {
         name: "test".to_string(),
       }),
       None,
-      Some(Token::IrString {
-        name: "hello".to_string(),
-      }),
-      None,
-      None,
+      vec![Some((
+        Token::IrString {
+          name: "hello".to_string(),
+        },
+        "'hello'".to_string(),
+      ))],
     );
 
     assert_eq!(directive, correct_instruction);
   }
+
+  #[test]
+  fn test_constant_directive() {
+    for source in &[
+      ".equ BUF_SIZE 4 * 8",
+      ".EQU BUF_SIZE #(4 * 8)",
+      "BUF_SIZE: .equ #(4*8)",
+    ] {
+      let (rest, directive) = directive(CompleteStr(source)).unwrap();
+      assert_eq!(rest, CompleteStr(""), "{}", source);
+      assert_eq!(directive.directive_name(), Some("equ".to_string()));
+      assert_eq!(directive.label_name(), Some("BUF_SIZE".to_string()));
+      assert_eq!(
+        directive.constant_expression().map(|e| e.to_string()),
+        Some("(4*8)".to_string())
+      );
+    }
+    let (_, settings) = directive(CompleteStr(".settings\nhlt")).unwrap();
+    assert_eq!(settings.directive_name(), Some("settings".to_string()));
+    assert_eq!(settings.label_name(), None);
+    let (rest, _) = directive(CompleteStr(".set X\n1")).unwrap();
+    assert_ne!(rest, CompleteStr(""));
+  }
 }
@@ -0,0 +1,226 @@
+use super::label_parser::identifier;
+use super::operand_parser::integer_literal;
+use super::{AssemblerErrorKind, SymbolTable};
+use nom::types::CompleteStr;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+}
+
+impl From<char> for BinaryOp {
+  fn from(c: char) -> BinaryOp {
+    match c {
+      '+' => BinaryOp::Add,
+      '-' => BinaryOp::Sub,
+      '*' => BinaryOp::Mul,
+      '/' => BinaryOp::Div,
+      _ => BinaryOp::Rem,
+    }
+  }
+}
+
+/// Operand expression, evaluated once every symbol is known.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+  Number(i64),
+  Symbol(String),
+  Negate(Box<Expr>),
+  Binary {
+    op: BinaryOp,
+    left: Box<Expr>,
+    right: Box<Expr>,
+  },
+  /// Bits 16 to 31 of the value, to build 32-bit constants from two halves.
+  Hi(Box<Expr>),
+  /// Bits 0 to 15 of the value.
+  Lo(Box<Expr>),
+}
+
+impl Expr {
+  pub fn eval(&self, symbols: &SymbolTable) -> Result<i64, AssemblerErrorKind> {
+    let value = match self {
+      Expr::Number(value) => Some(*value),
+      Expr::Symbol(name) => {
+        return symbols
+          .resolve(name)
+          .ok_or(AssemblerErrorKind::UnknownSymbol);
+      }
+      Expr::Negate(e) => e.eval(symbols)?.checked_neg(),
+      Expr::Binary { op, left, right } => {
+        let l = left.eval(symbols)?;
+        let r = right.eval(symbols)?;
+        match op {
+          BinaryOp::Add => l.checked_add(r),
+          BinaryOp::Sub => l.checked_sub(r),
+          BinaryOp::Mul => l.checked_mul(r),
+          BinaryOp::Div | BinaryOp::Rem if r == 0 => {
+            return Err(AssemblerErrorKind::DivisionByZero);
+          }
+          BinaryOp::Div => l.checked_div(r),
+          BinaryOp::Rem => l.checked_rem(r),
+        }
+      }
+      Expr::Hi(e) => Some((e.eval(symbols)? >> 16) & 0xffff),
+      Expr::Lo(e) => Some(e.eval(symbols)? & 0xffff),
+    };
+    value.ok_or(AssemblerErrorKind::ExpressionOverflow)
+  }
+
+  fn binary(left: Expr, (op, right): (char, Expr)) -> Expr {
+    Expr::Binary {
+      op: op.into(),
+      left: Box::new(left),
+      right: Box::new(right),
+    }
+  }
+}
+
+impl fmt::Display for Expr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Expr::Number(value) => write!(f, "{}", value),
+      Expr::Symbol(name) => write!(f, "{}", name),
+      Expr::Negate(e) => write!(f, "-{}", e),
+      Expr::Binary { op, left, right } => {
+        let op = match op {
+          BinaryOp::Add => "+",
+          BinaryOp::Sub => "-",
+          BinaryOp::Mul => "*",
+          BinaryOp::Div => "/",
+          BinaryOp::Rem => "%",
+        };
+        write!(f, "({}{}{})", left, op, right)
+      }
+      Expr::Hi(e) => write!(f, "hi({})", e),
+      Expr::Lo(e) => write!(f, "lo({})", e),
+    }
+  }
+}
+
+// Sums and differences of terms, with the usual precedence and left
+// associativity. Whitespace is allowed between the parts.
+named!(pub expression<CompleteStr, Expr>,
+  do_parse!(
+    first: term >>
+    rest: many0!(pair!(ws!(one_of!("+-")), term)) >>
+    (
+      rest.into_iter().fold(first, Expr::binary)
+    )
+  )
+);
+
+named!(term<CompleteStr, Expr>,
+  do_parse!(
+    first: unary >>
+    rest: many0!(pair!(ws!(one_of!("*/%")), unary)) >>
+    (
+      rest.into_iter().fold(first, Expr::binary)
+    )
+  )
+);
+
+named!(unary<CompleteStr, Expr>,
+  alt!(
+    map!(preceded!(ws!(char!('-')), unary), |e| Expr::Negate(Box::new(e))) |
+    atom
+  )
+);
+
+// A number, a parenthesized expression, `hi(...)`, `lo(...)` or a symbol,
+// optionally written as `@label`.
+named!(pub atom<CompleteStr, Expr>,
+  alt!(
+    map!(integer_literal, Expr::Number) |
+    do_parse!(
+      function: alt!(tag_no_case!("hi") | tag_no_case!("lo")) >>
+      argument: parenthesized >>
+      (
+        if function.eq_ignore_ascii_case("hi") {
+          Expr::Hi(Box::new(argument))
+        } else {
+          Expr::Lo(Box::new(argument))
+        }
+      )
+    ) |
+    parenthesized |
+    map!(preceded!(opt!(char!('@')), identifier), |name| Expr::Symbol(name.to_string()))
+  )
+);
+
+named!(parenthesized<CompleteStr, Expr>,
+  delimited!(ws!(char!('(')), expression, ws!(char!(')')))
+);
+
+// A label plus or minus offsets, as in `@table+8`, without whitespace so it
+// is not confused with the next operand.
+named!(pub label_expression<CompleteStr, Expr>,
+  do_parse!(
+    char!('@') >>
+    name: identifier >>
+    rest: many1!(pair!(one_of!("+-"), atom)) >>
+    (
+      rest.into_iter().fold(Expr::Symbol(name.to_string()), Expr::binary)
+    )
+  )
+);
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::{Symbol, SymbolType};
+
+  fn eval(text: &str, symbols: &SymbolTable) -> Result<i64, AssemblerErrorKind> {
+    let (rest, expr) = expression(CompleteStr(text)).unwrap();
+    assert_eq!(rest, CompleteStr(""), "{}", text);
+    expr.eval(symbols)
+  }
+
+  #[test]
+  fn test_evaluate_expressions() {
+    let mut symbols = SymbolTable::new();
+    symbols.add_symbol(Symbol::new(
+      "BUF_SIZE".to_string(),
+      16,
+      SymbolType::Constant,
+    ));
+    symbols.add_symbol(Symbol::new(
+      "table".to_string(),
+      0x12_3456,
+      SymbolType::Label,
+    ));
+    assert_eq!(eval("BUF_SIZE * 2 + 1", &symbols), Ok(33));
+    assert_eq!(eval("1+2*3-4", &symbols), Ok(3));
+    assert_eq!(eval("(1 + 2) * -3", &symbols), Ok(-9));
+    assert_eq!(eval("-BUF_SIZE % 5 - 0x10 / 4", &symbols), Ok(-5));
+    assert_eq!(eval("hi(@table)", &symbols), Ok(0x12));
+    assert_eq!(eval("lo(table) + 1", &symbols), Ok(0x3457));
+    assert_eq!(
+      eval("missing + 1", &symbols),
+      Err(AssemblerErrorKind::UnknownSymbol)
+    );
+    assert_eq!(
+      eval("BUF_SIZE / (2 - 2)", &symbols),
+      Err(AssemblerErrorKind::DivisionByZero)
+    );
+    assert_eq!(
+      eval("0x7fff_ffff_ffff_ffff + 1", &symbols),
+      Err(AssemblerErrorKind::ExpressionOverflow)
+    );
+  }
+
+  #[test]
+  fn test_parse_label_expression() {
+    let (rest, expr) = label_expression(CompleteStr("@table+8-1 #2")).unwrap();
+    assert_eq!(rest, CompleteStr(" #2"));
+    assert_eq!(expr.to_string(), "((table+8)-1)");
+    assert!(label_expression(CompleteStr("@table")).is_err());
+  }
+}
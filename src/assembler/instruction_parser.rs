@@ -1,10 +1,10 @@
 use super::directive_parser::directive;
+use super::expression_parser::Expr;
 use super::label_parser::*;
 use super::opcode_parser::*;
 use super::operand_parser::*;
-use super::register_parser::register;
 use super::*;
-use nom::multispace;
+use crate::instruction::OperandKind;
 use nom::types::CompleteStr;
 
 #[derive(Debug, PartialEq)]
@@ -15,6 +15,9 @@ pub struct AsmInstruction {
   operand3: Option<Token>,
   label: Option<Token>,
   directive: Option<Token>,
+  span: SourceSpan,
+  /// Source text of each operand, for diagnostics.
+  sources: Vec<String>,
 }
 
 named!(pub instruction<CompleteStr, AsmInstruction>,
@@ -30,18 +33,11 @@ named!(pub instruction_combined<CompleteStr, AsmInstruction>,
   do_parse!(
     l: opt!(label_declaration) >>
     o: opcode >>
-    o1: opt!(operand) >>
-    o2: opt!(operand) >>
-    o3: opt!(operand) >>
+    o1: opt!(call!(with_source, operand)) >>
+    o2: opt!(call!(with_source, operand)) >>
+    o3: opt!(call!(with_source, operand)) >>
     (
-      AsmInstruction{
-        opcode: Some(o),
-        label: l,
-        directive: None,
-        operand1: o1,
-        operand2: o2,
-        operand3: o3,
-      }
+      AsmInstruction::parsed(None, l, Some(o), vec![o1, o2, o3])
     )
   )
 );
@@ -56,72 +52,157 @@ impl AsmInstruction {
     operand3: Option<Token>,
   ) -> AsmInstruction {
     AsmInstruction {
-      directive: directive,
-      label: label,
-      opcode: opcode,
-      operand1: operand1,
-      operand2: operand2,
-      operand3: operand3,
+      directive,
+      label,
+      opcode,
+      operand1,
+      operand2,
+      operand3,
+      span: SourceSpan::default(),
+      sources: vec![],
     }
   }
 
-  pub fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
-    match t {
-      Token::Register { reg_num } => {
-        results.push(*reg_num);
-      }
-      Token::IntegerOperand { value } => {
-        let converted = *value as u16;
-        let byte1 = converted;
-        let byte2 = converted >> 8;
-        results.push(byte2 as u8);
-        results.push(byte1 as u8);
-      }
-      Token::LabelUsage { name } => {
-        if let Some(value) = symbols.symbol_value(name) {
-          let converted = value as u16;
-          let byte1 = converted;
-          let byte2 = converted >> 8;
-          results.push(byte2 as u8);
-          results.push(byte1 as u8);
-        } else {
-          println!("Symbol not found! [@{}]", name);
-          std::process::exit(1);
+  /// Instruction parsed from source, with the text of each operand.
+  pub fn parsed(
+    directive: Option<Token>,
+    label: Option<Token>,
+    opcode: Option<Token>,
+    operands: Vec<Option<(Token, String)>>,
+  ) -> AsmInstruction {
+    let (operands, sources): (Vec<Token>, Vec<String>) = operands.into_iter().flatten().unzip();
+    let mut operands = operands.into_iter();
+    AsmInstruction {
+      directive,
+      label,
+      opcode,
+      operand1: operands.next(),
+      operand2: operands.next(),
+      operand3: operands.next(),
+      span: SourceSpan::default(),
+      sources,
+    }
+  }
+
+  pub fn span(&self) -> &SourceSpan {
+    &self.span
+  }
+
+  pub fn set_span(&mut self, span: SourceSpan) {
+    self.span = span;
+  }
+
+  /// Checks that `t` can fill the operand slot `kind` and encodes it at the
+  /// slot's width.
+  pub fn extract_operand(
+    t: &Token,
+    kind: OperandKind,
+    results: &mut Vec<u8>,
+    symbols: &SymbolTable,
+  ) -> Result<(), AssemblerErrorKind> {
+    if kind == OperandKind::Float {
+      let value = match t {
+        Token::FloatOperand { value } => *value,
+        Token::IntegerOperand { value } => *value as f64,
+        Token::IntegerExpression { expr } => expr.eval(symbols)? as f64,
+        _ => return Err(AssemblerErrorKind::WrongOperandType { expected: kind }),
+      };
+      results.extend_from_slice(&value.to_bits().to_be_bytes());
+      return Ok(());
+    }
+    let value = match (kind, t) {
+      (OperandKind::Register, Token::Register { reg_num })
+      | (OperandKind::FloatRegister, Token::FloatRegister { reg_num }) => {
+        if *reg_num > kind.max_value() {
+          return Err(AssemblerErrorKind::RegisterOutOfRange);
         }
+        *reg_num as i64
       }
+      (OperandKind::Register, _) | (OperandKind::FloatRegister, _) => {
+        return Err(AssemblerErrorKind::WrongOperandType { expected: kind });
+      }
+      (_, Token::IntegerOperand { value }) => *value,
+      (_, Token::IntegerExpression { expr }) => expr.eval(symbols)?,
+      (OperandKind::Address, Token::LabelUsage { name })
+      | (OperandKind::String, Token::LabelUsage { name }) => match symbols.resolve(name) {
+        Some(value) => value,
+        None => return Err(AssemblerErrorKind::UnknownSymbol),
+      },
+      (OperandKind::Address, Token::LabelExpression { expr })
+      | (OperandKind::String, Token::LabelExpression { expr }) => expr.eval(symbols)?,
       _ => {
-        println!("Opcode found in operand field");
-        std::process::exit(1);
+        return Err(AssemblerErrorKind::WrongOperandType { expected: kind });
       }
     };
+    if value < kind.min_value() || value > kind.max_value() as i64 {
+      return Err(AssemblerErrorKind::ImmediateOutOfRange);
+    }
+    let bytes = (value as u32).to_be_bytes();
+    results.extend_from_slice(&bytes[bytes.len() - kind.width()..]);
+    Ok(())
   }
 
-  pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
-    let mut results: Vec<u8> = vec![];
-    match &self.opcode {
-      Some(t) => match t {
-        Token::Op { code } => {
-          results.push(*code as u8);
-        }
-        _ => {
-          println!("Non-opcode found in opcode field");
-          std::process::exit(1);
-        }
-      },
-      None => (),
+  pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
+    let info = match &self.opcode {
+      Some(Token::Op { code }) => code.info().ok_or_else(|| {
+        self
+          .span
+          .error(AssemblerErrorKind::UnknownOpcode, self.span.text.trim())
+      })?,
+      Some(t) => {
+        return Err(
+          self
+            .span
+            .error(AssemblerErrorKind::UnexpectedToken, &t.to_string()),
+        );
+      }
+      None => return Ok(vec![]),
     };
 
-    for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-      if let Some(t) = operand {
-        AsmInstruction::extract_operand(&t, &mut results, symbols)
-      }
+    let operands: Vec<&Token> = [&self.operand1, &self.operand2, &self.operand3]
+      .iter()
+      .filter_map(|t| t.as_ref())
+      .collect();
+    if operands.len() != info.operands.len() {
+      return Err(
+        self
+          .span
+          .error(AssemblerErrorKind::BadOperandCount, self.span.text.trim()),
+      );
     }
 
-    while results.len() < 4 {
-      results.push(0);
+    let mut results: Vec<u8> = vec![info.code];
+    for (index, (t, kind)) in operands.iter().zip(info.operands).enumerate() {
+      AsmInstruction::extract_operand(t, *kind, &mut results, symbols)
+        .map_err(|kind| self.operand_error(index, kind))?;
     }
+    results.resize(info.width(), 0);
+
+    Ok(results)
+  }
 
-    results
+  /// Error about operand `index`, pointing at its text in the source. The
+  /// operands end the instruction, so they are looked for from the end.
+  pub fn operand_error(&self, index: usize, kind: AssemblerErrorKind) -> AssemblerError {
+    let source = match self.sources.get(index) {
+      Some(source) => source,
+      None => {
+        // Built by hand rather than parsed
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        let text = operands.iter().flat_map(|t| t.iter()).nth(index);
+        return self
+          .span
+          .error(kind, &text.map_or_else(String::new, Token::to_string));
+      }
+    };
+    let mut end = self.span.text.len();
+    for operand in self.sources[index..].iter().rev() {
+      match self.span.text[..end].rfind(operand.as_str()) {
+        Some(start) => end = start,
+        None => return self.span.error(kind, source),
+      }
+    }
+    self.span.error_at(kind, end, source)
   }
 
   pub fn is_label(&self) -> bool {
@@ -130,11 +211,8 @@ impl AsmInstruction {
 
   pub fn label_name(&self) -> Option<String> {
     match &self.label {
-      Some(t) => match t {
-        Token::LabelDeclaration { name } => Some(name.to_string()),
-        _ => None,
-      },
-      None => None,
+      Some(Token::LabelDeclaration { name }) => Some(name.to_string()),
+      _ => None,
     }
   }
 
@@ -144,11 +222,16 @@ impl AsmInstruction {
 
   pub fn directive_name(&self) -> Option<String> {
     match &self.directive {
-      Some(t) => match t {
-        Token::Directive { name } => Some(name.to_string()),
-        _ => None,
-      },
-      None => None,
+      Some(Token::Directive { name }) => Some(name.to_string()),
+      _ => None,
+    }
+  }
+
+  /// Encoded width of the instruction, zero for directives.
+  pub fn width(&self) -> usize {
+    match &self.opcode {
+      Some(Token::Op { code }) => code.width(),
+      _ => 0,
     }
   }
 
@@ -160,13 +243,29 @@ impl AsmInstruction {
     self.operand1.is_some() || self.operand2.is_some() || self.operand3.is_some()
   }
 
+  pub fn operand_count(&self) -> usize {
+    [&self.operand1, &self.operand2, &self.operand3]
+      .iter()
+      .filter(|o| o.is_some())
+      .count()
+  }
+
+  /// Value of a `.equ` or `.set` directive.
+  pub fn constant_expression(&self) -> Option<Expr> {
+    match &self.operand1 {
+      Some(Token::IntegerOperand { value }) => Some(Expr::Number(*value)),
+      Some(Token::IntegerExpression { expr }) | Some(Token::LabelExpression { expr }) => {
+        Some(expr.clone())
+      }
+      Some(Token::LabelUsage { name }) => Some(Expr::Symbol(name.to_string())),
+      _ => None,
+    }
+  }
+
   pub fn get_string_constant(&self) -> Option<String> {
     match &self.operand1 {
-      Some(d) => match d {
-        Token::Directive { name } => Some(name.to_string()),
-        _ => None,
-      },
-      None => None,
+      Some(Token::IrString { name }) => Some(name.to_string()),
+      _ => None,
     }
   }
 }
@@ -189,7 +288,9 @@ mod tests {
           operand2: Some(Token::IntegerOperand { value: 100 }),
           operand3: None,
           label: None,
-          directive: None
+          directive: None,
+          span: SourceSpan::default(),
+          sources: vec!["$0".to_string(), "#100".to_string()],
         }
       ))
     );
@@ -200,7 +301,7 @@ mod tests {
     let tok = Token::Register { reg_num: 5 };
     let mut v: Vec<u8> = vec![];
     let symbols = SymbolTable::new();
-    AsmInstruction::extract_operand(&tok, &mut v, &symbols);
+    AsmInstruction::extract_operand(&tok, OperandKind::Register, &mut v, &symbols).unwrap();
     assert_eq!(v.len(), 1);
     assert_eq!(v[0], 5);
   }
@@ -210,7 +311,7 @@ mod tests {
     let tok = Token::IntegerOperand { value: 255 };
     let mut v: Vec<u8> = vec![];
     let symbols = SymbolTable::new();
-    AsmInstruction::extract_operand(&tok, &mut v, &symbols);
+    AsmInstruction::extract_operand(&tok, OperandKind::Integer16, &mut v, &symbols).unwrap();
     assert_eq!(v.len(), 2);
     assert_eq!(v[0], 0);
     assert_eq!(v[1], 255);
@@ -225,10 +326,12 @@ mod tests {
       operand3: None,
       label: None,
       directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
     };
 
     let symbols = SymbolTable::new();
-    let res = inst.to_bytes(&symbols);
+    let res = inst.to_bytes(&symbols).unwrap();
     assert_eq!(res.len(), 4);
     assert_eq!(res[0], 0);
   }
@@ -242,9 +345,11 @@ mod tests {
       operand3: None,
       label: None,
       directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
     };
     let symbols = SymbolTable::new();
-    let res = inst.to_bytes(&symbols);
+    let res = inst.to_bytes(&symbols).unwrap();
     assert_eq!(res.len(), 4);
     assert_eq!(res, vec![1, 0, 0, 50]);
   }
@@ -258,9 +363,11 @@ mod tests {
       operand3: Some(Token::Register { reg_num: 2 }),
       label: None,
       directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
     };
     let symbols = SymbolTable::new();
-    let res = inst.to_bytes(&symbols);
+    let res = inst.to_bytes(&symbols).unwrap();
     assert_eq!(res.len(), 4);
     assert_eq!(res, vec![2, 0, 1, 2]);
   }
@@ -274,9 +381,11 @@ mod tests {
       operand3: None,
       label: None,
       directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
     };
     let symbols = SymbolTable::new();
-    let res = inst.to_bytes(&symbols);
+    let res = inst.to_bytes(&symbols).unwrap();
     assert_eq!(res.len(), 4);
     assert_eq!(res, vec![9, 0, 1, 0]);
   }
@@ -290,10 +399,166 @@ mod tests {
       operand3: None,
       label: None,
       directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
     };
     let symbols = SymbolTable::new();
-    let res = inst.to_bytes(&symbols);
+    let res = inst.to_bytes(&symbols).unwrap();
     assert_eq!(res.len(), 4);
     assert_eq!(res, vec![16, 0, 0, 0]);
   }
+
+  #[test]
+  fn test_heap_operation_with_offset() {
+    let inst = AsmInstruction {
+      opcode: Some(Token::Op { code: Opcode::LDW }),
+      operand1: Some(Token::Register { reg_num: 0 }),
+      operand2: Some(Token::Register { reg_num: 1 }),
+      operand3: Some(Token::IntegerOperand { value: 8 }),
+      label: None,
+      directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
+    };
+    let symbols = SymbolTable::new();
+    let res = inst.to_bytes(&symbols).unwrap();
+    assert_eq!(res, vec![26, 0, 1, 8]);
+  }
+
+  #[test]
+  fn test_heap_operation_offset_out_of_range() {
+    let inst = AsmInstruction {
+      opcode: Some(Token::Op { code: Opcode::STB }),
+      operand1: Some(Token::Register { reg_num: 0 }),
+      operand2: Some(Token::Register { reg_num: 1 }),
+      operand3: Some(Token::IntegerOperand { value: 256 }),
+      label: None,
+      directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
+    };
+    let symbols = SymbolTable::new();
+    let res = inst.to_bytes(&symbols);
+    assert_eq!(
+      res.unwrap_err().kind,
+      AssemblerErrorKind::ImmediateOutOfRange
+    );
+  }
+
+  #[test]
+  fn test_operand_count_must_match_opcode() {
+    let inst = AsmInstruction {
+      opcode: Some(Token::Op { code: Opcode::HLT }),
+      operand1: Some(Token::Register { reg_num: 3 }),
+      operand2: None,
+      operand3: None,
+      label: None,
+      directive: None,
+      span: SourceSpan::default(),
+      sources: vec![],
+    };
+    let symbols = SymbolTable::new();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap_err().kind,
+      AssemblerErrorKind::BadOperandCount
+    );
+  }
+
+  #[test]
+  fn test_unknown_opcode() {
+    let inst = AsmInstruction::new(
+      None,
+      None,
+      Some(Token::Op { code: Opcode::IGL }),
+      None,
+      None,
+      None,
+    );
+    let symbols = SymbolTable::new();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap_err().kind,
+      AssemblerErrorKind::UnknownOpcode
+    );
+  }
+
+  #[test]
+  fn test_operand_types_must_match_opcode() {
+    let symbols = SymbolTable::new();
+    let mut v = vec![];
+    assert_eq!(
+      AsmInstruction::extract_operand(
+        &Token::IntegerOperand { value: 5 },
+        OperandKind::Register,
+        &mut v,
+        &symbols
+      ),
+      Err(AssemblerErrorKind::WrongOperandType {
+        expected: OperandKind::Register
+      })
+    );
+    assert_eq!(
+      AsmInstruction::extract_operand(
+        &Token::LabelUsage {
+          name: "x".to_string()
+        },
+        OperandKind::Integer16,
+        &mut v,
+        &symbols
+      ),
+      Err(AssemblerErrorKind::WrongOperandType {
+        expected: OperandKind::Integer16
+      })
+    );
+    assert_eq!(
+      AsmInstruction::extract_operand(
+        &Token::Register { reg_num: 32 },
+        OperandKind::Register,
+        &mut v,
+        &symbols
+      ),
+      Err(AssemblerErrorKind::RegisterOutOfRange)
+    );
+    assert_eq!(
+      AsmInstruction::extract_operand(
+        &Token::IntegerOperand { value: 65536 },
+        OperandKind::Integer16,
+        &mut v,
+        &symbols
+      ),
+      Err(AssemblerErrorKind::ImmediateOutOfRange)
+    );
+    assert!(v.is_empty());
+  }
+
+  #[test]
+  fn test_float_instruction_to_bytes() {
+    let symbols = SymbolTable::new();
+    let (_, inst) = instruction(CompleteStr("ldf $f2 #1.5")).unwrap();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap(),
+      vec![50, 2, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+    let (_, inst) = instruction(CompleteStr("addf $f0 $1 $f2")).unwrap();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap_err().kind,
+      AssemblerErrorKind::WrongOperandType {
+        expected: OperandKind::FloatRegister
+      }
+    );
+  }
+
+  #[test]
+  fn test_wide_immediate_to_bytes() {
+    let symbols = SymbolTable::new();
+    let (_, inst) = instruction(CompleteStr("ldi $3 #70000")).unwrap();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap(),
+      vec![58, 3, 0, 1, 0x11, 0x70, 0, 0]
+    );
+    let (_, inst) = instruction(CompleteStr("ld $3 #70000")).unwrap();
+    assert_eq!(
+      inst.to_bytes(&symbols).unwrap_err().kind,
+      AssemblerErrorKind::ImmediateOutOfRange
+    );
+  }
 }
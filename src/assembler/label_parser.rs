@@ -1,12 +1,21 @@
+use nom::multispace;
 use nom::types::CompleteStr;
-use nom::{alphanumeric, multispace};
 
 use super::Token;
 
+// Name of a label or constant: letters, digits and underscores, not
+// starting with a digit.
+named!(pub identifier<CompleteStr, CompleteStr>,
+  recognize!(pair!(
+    take_while1!(|c: char| c.is_alphabetic() || c == '_'),
+    take_while!(|c: char| c.is_alphanumeric() || c == '_')
+  ))
+);
+
 named!(pub label_declaration<CompleteStr, Token>,
   ws!(
     do_parse!(
-      name: alphanumeric >>
+      name: identifier >>
       tag!(":") >>
       opt!(multispace) >>
       (
@@ -20,7 +29,7 @@ named!(pub label_usage<CompleteStr, Token>,
   ws!(
     do_parse!(
       tag!("@") >>
-      name: alphanumeric >>
+      name: identifier >>
       opt!(multispace) >>
       (
         Token::LabelUsage{name: name.to_string()}
@@ -46,7 +55,15 @@ mod tests {
     );
 
     let result = label_declaration(CompleteStr("invalid_label"));
-    assert!(!result.is_ok());
+    assert!(result.is_err());
+
+    let result = label_declaration(CompleteStr("_with_underscores2:"));
+    assert_eq!(
+      result.map(|(_, label)| label),
+      Ok(Token::LabelDeclaration {
+        name: "_with_underscores2".to_string()
+      })
+    );
   }
 
   #[test]
@@ -62,6 +79,6 @@ mod tests {
     );
 
     let result = label_usage(CompleteStr("invalid_label_usage:"));
-    assert!(!result.is_ok());
+    assert!(result.is_err());
   }
 }
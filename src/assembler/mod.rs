@@ -1,10 +1,11 @@
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
-
 use super::instruction::Opcode;
-use nom::types::CompleteStr;
+use super::pie::{PieHeader, PIE_HEADER_LENGTH};
+use std::collections::HashSet;
+use std::fmt;
 
+pub mod assembler_errors;
 pub mod directive_parser;
+pub mod expression_parser;
 pub mod instruction_parser;
 pub mod label_parser;
 pub mod opcode_parser;
@@ -12,46 +13,129 @@ pub mod operand_parser;
 pub mod program_parser;
 pub mod register_parser;
 
+pub use assembler_errors::{AssemblerError, AssemblerErrorKind};
+use expression_parser::Expr;
 use instruction_parser::*;
 use program_parser::*;
 
+/// Label where execution starts, defaulting to the start of the code.
+pub const ENTRY_POINT_LABEL: &str = "main";
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
-  Op { code: Opcode },
-  Register { reg_num: u8 },
-  IntegerOperand { value: i32 },
-  LabelDeclaration { name: String },
-  LabelUsage { name: String },
-  Directive { name: String },
-  IrString { name: String },
+  Op {
+    code: Opcode,
+  },
+  Register {
+    reg_num: u32,
+  },
+  FloatRegister {
+    reg_num: u32,
+  },
+  IntegerOperand {
+    value: i64,
+  },
+  FloatOperand {
+    value: f64,
+  },
+  LabelDeclaration {
+    name: String,
+  },
+  LabelUsage {
+    name: String,
+  },
+  Directive {
+    name: String,
+  },
+  IrString {
+    name: String,
+  },
+  /// `#(...)`, `#hi(...)` or `#NAME`, usable wherever an integer is.
+  IntegerExpression {
+    expr: Expr,
+  },
+  /// `@label+offset`, usable wherever a label is.
+  LabelExpression {
+    expr: Expr,
+  },
 }
 
-#[derive(Debug, PartialEq)]
-pub enum AssemblerPhase {
-  First,
-  Second,
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::Op { code } => write!(f, "{:?}", code),
+      Token::Register { reg_num } => write!(f, "${}", reg_num),
+      Token::FloatRegister { reg_num } => write!(f, "$f{}", reg_num),
+      Token::IntegerOperand { value } => write!(f, "#{}", value),
+      Token::FloatOperand { value } => write!(f, "#{:?}", value),
+      Token::LabelDeclaration { name } => write!(f, "{}:", name),
+      Token::LabelUsage { name } => write!(f, "@{}", name),
+      Token::Directive { name } => write!(f, ".{}", name),
+      Token::IrString { name } => write!(f, "'{}'", name),
+      Token::IntegerExpression { expr } => write!(f, "#{}", expr),
+      Token::LabelExpression { expr } => write!(f, "@{}", expr),
+    }
+  }
 }
 
-impl Default for AssemblerPhase {
-  fn default() -> Self {
-    AssemblerPhase::First
+/// Position of a parsed instruction in the assembler source, used to point
+/// diagnostics at the offending text.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SourceSpan {
+  pub line: usize,
+  pub column: usize,
+  pub text: String,
+}
+
+impl SourceSpan {
+  pub fn new(source: &str, offset: usize, text: &str) -> SourceSpan {
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    SourceSpan {
+      line: before.matches('\n').count() + 1,
+      column: before[line_start..].chars().count() + 1,
+      text: text.to_string(),
+    }
+  }
+
+  /// Builds an error for `offending`, pointing at its first occurrence within
+  /// the span when it can be found.
+  pub fn error(&self, kind: AssemblerErrorKind, offending: &str) -> AssemblerError {
+    self.error_at(kind, self.text.find(offending).unwrap_or(0), offending)
+  }
+
+  /// Builds an error for `offending`, found at byte `offset` of the span.
+  pub fn error_at(
+    &self,
+    kind: AssemblerErrorKind,
+    offset: usize,
+    offending: &str,
+  ) -> AssemblerError {
+    let column = self.column + self.text[..offset].chars().count();
+    AssemblerError::new(kind, self.line, column, offending)
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum AssemblerSection {
-  Data { starting_instruction: Option<u32> },
-  Code { starting_instruction: Option<u32> },
-  Unknown,
+#[derive(Debug, PartialEq, Default)]
+pub enum AssemblerPhase {
+  #[default]
+  First,
+  Second,
 }
 
-impl Default for AssemblerSection {
-  fn default() -> Self {
-    AssemblerSection::Unknown
-  }
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum AssemblerSection {
+  Data {
+    starting_instruction: Option<u32>,
+  },
+  Code {
+    starting_instruction: Option<u32>,
+  },
+  #[default]
+  Unknown,
 }
 
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
   fn from(name: &str) -> AssemblerSection {
     match name {
       "data" => AssemblerSection::Data {
@@ -65,26 +149,49 @@ impl<'a> From<&'a str> for AssemblerSection {
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum SymbolType {
   Label,
+  /// Value defined with `.equ` or `.set`.
+  Constant,
 }
 
 #[derive(Debug)]
 pub struct Symbol {
   name: String,
-  offset: u32,
+  value: i64,
   symbol_type: SymbolType,
 }
 
 impl Symbol {
-  pub fn new(name: String, offset: u32, symbol_type: SymbolType) -> Symbol {
+  pub fn new(name: String, value: i64, symbol_type: SymbolType) -> Symbol {
     Symbol {
-      name: name,
-      offset: offset,
-      symbol_type: symbol_type,
+      name,
+      value,
+      symbol_type,
     }
   }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Offset of a label.
+  pub fn offset(&self) -> u32 {
+    self.value as u32
+  }
+
+  pub fn value(&self) -> i64 {
+    self.value
+  }
+
+  pub fn is_label(&self) -> bool {
+    self.symbol_type == SymbolType::Label
+  }
+
+  pub fn symbol_type(&self) -> &SymbolType {
+    &self.symbol_type
+  }
 }
 
 #[derive(Debug)]
@@ -92,6 +199,12 @@ pub struct SymbolTable {
   symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
 impl SymbolTable {
   pub fn new() -> SymbolTable {
     SymbolTable { symbols: vec![] }
@@ -101,19 +214,52 @@ impl SymbolTable {
     self.symbols.push(symbol);
   }
 
+  pub fn has_symbol(&self, s: &str) -> bool {
+    self.symbols.iter().any(|symbol| symbol.name == s)
+  }
+
+  /// Offset of the label `s`.
   pub fn symbol_value(&self, s: &str) -> Option<u32> {
-    for symbol in &self.symbols {
-      if symbol.name == s {
-        return Some(symbol.offset);
-      }
-    }
-    None
+    self
+      .labels()
+      .find(|symbol| symbol.name == s)
+      .map(Symbol::offset)
+  }
+
+  /// Value of the label or constant `s`.
+  pub fn resolve(&self, s: &str) -> Option<i64> {
+    self.get(s).map(Symbol::value)
+  }
+
+  pub fn get(&self, s: &str) -> Option<&Symbol> {
+    self.symbols.iter().find(|symbol| symbol.name == s)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+    self.symbols.iter()
+  }
+
+  pub fn labels(&self) -> impl Iterator<Item = &Symbol> {
+    self.symbols.iter().filter(|symbol| symbol.is_label())
+  }
+
+  /// First label defined at `offset`.
+  pub fn symbol_at(&self, offset: u32) -> Option<&Symbol> {
+    self.labels().find(|symbol| symbol.offset() == offset)
   }
 
   pub fn set_symbol_offset(&mut self, s: &str, offset: u32) -> bool {
+    self.set_symbol_value(s, offset as i64)
+  }
+
+  pub fn remove_symbol(&mut self, s: &str) {
+    self.symbols.retain(|symbol| symbol.name != s);
+  }
+
+  pub fn set_symbol_value(&mut self, s: &str, value: i64) -> bool {
     for symbol in &mut self.symbols {
       if symbol.name == s {
-        symbol.offset = offset;
+        symbol.value = value;
         return true;
       }
     }
@@ -131,6 +277,14 @@ pub struct Assembler {
   sections: Vec<AssemblerSection>,
   current_section: Option<AssemblerSection>,
   current_instruction: u32,
+  variables: HashSet<String>,
+  errors: Vec<AssemblerError>,
+}
+
+impl Default for Assembler {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Assembler {
@@ -144,40 +298,122 @@ impl Assembler {
       sections: vec![],
       current_section: None,
       current_instruction: 0,
+      variables: HashSet::new(),
+      errors: vec![],
     }
   }
 
+  /// Registers the labels of code instructions. Code is placed after the
+  /// header and the read-only data, so this must run once every string has
+  /// been collected.
   fn extract_labels(&mut self, p: &Program) {
-    let mut c = 0;
+    let mut c = (PIE_HEADER_LENGTH + self.ro.len()) as u32;
     for i in &p.instructions {
-      if i.is_label() {
-        match i.label_name() {
-          Some(name) => {
-            let symbol = Symbol::new(name, c, SymbolType::Label);
-            self.symbols.add_symbol(symbol);
-          }
-          None => {}
+      if !i.is_opcode() {
+        continue;
+      }
+      if let Some(name) = i.label_name() {
+        self.add_label(i, name, c);
+      }
+
+      c += i.width() as u32;
+    }
+  }
+
+  fn add_label(&mut self, i: &AsmInstruction, name: String, offset: u32) {
+    if self.symbols.has_symbol(&name) {
+      let text = format!("{}:", name);
+      self.error(i, AssemblerErrorKind::DuplicateLabel, &text);
+      return;
+    }
+    let symbol = Symbol::new(name, offset as i64, SymbolType::Label);
+    self.symbols.add_symbol(symbol);
+  }
+
+  /// Defines the `.equ` constants and the first value of the `.set` ones,
+  /// once the labels they may refer to are known. A constant can only use
+  /// the constants defined above it.
+  fn define_constants(&mut self, p: &Program) {
+    for i in &p.instructions {
+      let redefinable = match i.directive_name().as_deref() {
+        Some("equ") => false,
+        Some("set") => true,
+        _ => continue,
+      };
+      let (name, expr) = match (i.label_name(), i.constant_expression()) {
+        (Some(name), Some(expr)) => (name, expr),
+        _ => continue,
+      };
+      if redefinable && self.variables.contains(&name) {
+        // Keeps the value current for the `.equ`s that follow.
+        if let Ok(value) = expr.eval(&self.symbols) {
+          self.assign_variable(&name, value);
         }
+        continue;
+      }
+      if self.symbols.has_symbol(&name) {
+        self.error(i, AssemblerErrorKind::DuplicateLabel, &name);
+        continue;
       }
+      match expr.eval(&self.symbols) {
+        Ok(value) => {
+          self
+            .symbols
+            .add_symbol(Symbol::new(name.clone(), value, SymbolType::Constant));
+        }
+        // Errors in `.set`s are reported by the second phase.
+        Err(_) if redefinable => {}
+        Err(kind) => self.errors.push(i.operand_error(0, kind)),
+      }
+      if redefinable {
+        self.variables.insert(name);
+      }
+    }
+    // The second phase defines them again from the top, so using one before
+    // its first `.set` is an unknown symbol.
+    for name in &self.variables {
+      self.symbols.remove_symbol(name);
+    }
+  }
 
-      c += 4;
+  fn assign_variable(&mut self, name: &str, value: i64) {
+    if !self.symbols.set_symbol_value(name, value) {
+      self
+        .symbols
+        .add_symbol(Symbol::new(name.to_string(), value, SymbolType::Constant));
     }
   }
 
   fn process_first_phase(&mut self, p: &Program) {
+    for i in &p.instructions {
+      if i.is_directive() {
+        self.process_directive(i);
+      }
+    }
     self.extract_labels(p);
+    self.define_constants(p);
     self.phase = AssemblerPhase::Second;
   }
 
   fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
     let mut program = vec![];
     for i in &p.instructions {
-      if i.is_opcode() {
-        let mut bytes = i.to_bytes(&self.symbols);
-        program.append(&mut bytes);
+      if i.directive_name().as_deref() == Some("set") {
+        // `.set`s apply from where they appear.
+        if let (Some(name), Some(expr)) = (i.label_name(), i.constant_expression()) {
+          if self.variables.contains(&name) {
+            match expr.eval(&self.symbols) {
+              Ok(value) => self.assign_variable(&name, value),
+              Err(kind) => self.errors.push(i.operand_error(0, kind)),
+            }
+          }
+        }
       }
-      if i.is_directive() {
-        self.process_directive(i);
+      if i.is_opcode() {
+        match i.to_bytes(&self.symbols) {
+          Ok(mut bytes) => program.append(&mut bytes),
+          Err(e) => self.errors.push(e),
+        }
       }
 
       self.current_instruction += 1;
@@ -185,67 +421,92 @@ impl Assembler {
     program
   }
 
-  pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-    match program(CompleteStr(raw)) {
-      Ok((_, program)) => {
-        let mut assembled_program = self.write_pie_header();
-        self.process_first_phase(&program);
+  /// Assembles `raw` into a PIE image. Every problem found in the source is
+  /// reported, ordered by position.
+  pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+    let (program, mut errors) = parse_program(raw);
+    self.errors.append(&mut errors);
 
-        let mut body = self.process_second_phase(&program);
+    self.process_first_phase(&program);
 
-        if self.sections.len() < 2 {
-          println!("Did not found at least 2 sections");
-          // std::process::exit(1);
-        }
+    let mut body = self.process_second_phase(&program);
 
-        assembled_program.append(&mut body);
-        Some(assembled_program)
-      }
-      Err(e) => {
-        println!("Error assembling code: {:?}", e);
-        None
-      }
+    if !self.errors.is_empty() {
+      let mut errors = std::mem::take(&mut self.errors);
+      errors.sort_by_key(|e| (e.line, e.column));
+      return Err(errors);
     }
+
+    let mut assembled_program = self.write_pie_header(body.len());
+    assembled_program.extend_from_slice(&self.ro);
+    assembled_program.append(&mut body);
+    Ok(assembled_program)
   }
 
-  fn write_pie_header(&self) -> Vec<u8> {
-    let mut header = vec![];
-    for byte in PIE_HEADER_PREFIX.iter() {
-      header.push(byte.clone());
+  /// Builds the header for the read-only data followed by `code_length`
+  /// bytes of code. Execution enters at the `main` label when there is one.
+  fn write_pie_header(&self, code_length: usize) -> Vec<u8> {
+    let mut header = PieHeader::new(self.ro.len() as u32, code_length as u32);
+    if let Some(entry_point) = self.symbols.symbol_value(ENTRY_POINT_LABEL) {
+      header.entry_point = entry_point;
     }
-    while header.len() < PIE_HEADER_LENGTH {
-      header.push(0 as u8);
-    }
-    header
+    header.to_bytes()
   }
 
   fn process_directive(&mut self, i: &AsmInstruction) {
     let directive_name = match i.directive_name() {
       Some(d) => d,
-      None => {
-        println!("Directive has invalid name: {:?}", i);
-        return;
-      }
+      None => return,
     };
-
-    if i.has_operands() {
-      match directive_name.as_ref() {
-        "asciiz" => {
+    let text = format!(".{}", directive_name);
+
+    match directive_name.as_ref() {
+      "asciiz" => {
+        if i.operand_count() != 1 {
+          self.error(i, AssemblerErrorKind::BadOperandCount, &text);
+        } else if !i.is_label() {
+          self.error(i, AssemblerErrorKind::MissingLabel, &text);
+        } else {
           self.handle_asciiz(i);
         }
-        _ => {
-          println!("Unknown directive {}", directive_name);
+      }
+      "equ" | "set" => {
+        if i.operand_count() != 1 {
+          self.error(i, AssemblerErrorKind::BadOperandCount, &text);
+        } else if !i.is_label() {
+          self.error(i, AssemblerErrorKind::MissingLabel, &text);
+        } else if i.constant_expression().is_none() {
+          self.error(i, AssemblerErrorKind::UnexpectedToken, &text);
         }
       }
-    } else {
-      self.process_section_header(&directive_name);
+      "data" | "code" if i.has_operands() => {
+        self.error(i, AssemblerErrorKind::BadOperandCount, &text);
+      }
+      _ if i.has_operands() => {
+        self.error(i, AssemblerErrorKind::UnknownDirective, &text);
+      }
+      _ => {
+        if let Some(name) = i.label_name() {
+          self.error(
+            i,
+            AssemblerErrorKind::UnexpectedLabel,
+            &format!("{}:", name),
+          );
+        }
+        self.process_section_header(i, &directive_name);
+      }
     }
   }
 
-  fn process_section_header(&mut self, header_name: &str) {
+  fn error(&mut self, i: &AsmInstruction, kind: AssemblerErrorKind, text: &str) {
+    self.errors.push(i.span().error(kind, text));
+  }
+
+  fn process_section_header(&mut self, i: &AsmInstruction, header_name: &str) {
     let new_section: AssemblerSection = header_name.into();
     if new_section == AssemblerSection::Unknown {
-      println!("Found unkown section header: {:#?}", header_name);
+      let text = format!(".{}", header_name);
+      self.error(i, AssemblerErrorKind::UnknownSection, &text);
       return;
     }
 
@@ -260,13 +521,13 @@ impl Assembler {
 
     match i.get_string_constant() {
       Some(s) => {
-        match i.label_name() {
-          Some(name) => self.symbols.set_symbol_offset(&name, self.ro_offset),
-          None => {
-            println!("Found string with no associated label");
-            return;
+        if let Some(name) = i.label_name() {
+          if name == ENTRY_POINT_LABEL {
+            let text = format!("{}:", name);
+            self.error(i, AssemblerErrorKind::EntryPointNotCode, &text);
           }
-        };
+          self.add_label(i, name, self.ro_offset);
+        }
         for byte in s.as_bytes() {
           self.ro.push(*byte);
           self.ro_offset += 1;
@@ -275,7 +536,7 @@ impl Assembler {
         self.ro_offset += 1;
       }
       None => {
-        println!("Empty string constant after .asciiz");
+        self.error(i, AssemblerErrorKind::UnexpectedToken, ".asciiz");
       }
     };
   }
@@ -284,6 +545,7 @@ impl Assembler {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::instruction::OperandKind;
 
   #[test]
   fn test_symbol_table() {
@@ -294,14 +556,288 @@ mod tests {
     assert!(value.is_some());
     let value = sym.symbol_value("wrong");
     assert!(value.is_none());
+    assert_eq!(sym.symbol_at(32).map(|s| s.name()), Some("test"));
+    assert!(sym.symbol_at(36).is_none());
   }
 
   #[test]
   fn test_assemble_program() {
     let mut asm = Assembler::new();
-    let test_string =
-      "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
+    let test_string = "ld $0 #100\nld $1 #1\nld $2 #0\ntest: inc $0\nneq $0 $2\njne @test\nhlt";
     let program = asm.assemble(test_string).unwrap();
     assert_eq!(program.len(), 92);
   }
+
+  #[test]
+  fn test_assemble_reports_all_errors() {
+    let mut asm = Assembler::new();
+    let test_string = "start: ld $0 #1\njmp @nowhere\nstart: hlt\n.bogus 'x'\n.text\n.asciiz 'x'";
+    let errors = asm.assemble(test_string).unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(AssemblerErrorKind::UnknownSymbol, 2, 5, "@nowhere"),
+        AssemblerError::new(AssemblerErrorKind::DuplicateLabel, 3, 1, "start:"),
+        AssemblerError::new(AssemblerErrorKind::UnknownDirective, 4, 1, ".bogus"),
+        AssemblerError::new(AssemblerErrorKind::UnknownSection, 5, 1, ".text"),
+        AssemblerError::new(AssemblerErrorKind::MissingLabel, 6, 1, ".asciiz"),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_assemble_reports_parse_errors() {
+    let mut asm = Assembler::new();
+    let errors = asm.assemble("ld $0 #1\n  ld $1 @\n").unwrap_err();
+    assert_eq!(
+      errors,
+      vec![AssemblerError::new(
+        AssemblerErrorKind::ParseError,
+        2,
+        9,
+        "@"
+      )]
+    );
+  }
+
+  #[test]
+  fn test_assemble_checks_opcode_table() {
+    let mut asm = Assembler::new();
+    let errors = asm
+      .assemble(
+        "hlt $3
+load $0 #1
+add $0 $1",
+      )
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(AssemblerErrorKind::BadOperandCount, 1, 1, "hlt $3"),
+        AssemblerError::new(AssemblerErrorKind::UnknownOpcode, 2, 1, "load $0 #1"),
+        AssemblerError::new(AssemblerErrorKind::BadOperandCount, 3, 1, "add $0 $1"),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_assemble_checks_operand_types() {
+    let mut asm = Assembler::new();
+    let errors = asm
+      .assemble("add $0 #5 $1\nld $40 #1\nld $1 #65536\nprts $2\njmp $1\ninc $300")
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(
+          AssemblerErrorKind::WrongOperandType {
+            expected: OperandKind::Register
+          },
+          1,
+          8,
+          "#5"
+        ),
+        AssemblerError::new(AssemblerErrorKind::RegisterOutOfRange, 2, 4, "$40"),
+        AssemblerError::new(AssemblerErrorKind::ImmediateOutOfRange, 3, 7, "#65536"),
+        AssemblerError::new(
+          AssemblerErrorKind::WrongOperandType {
+            expected: OperandKind::String
+          },
+          4,
+          6,
+          "$2"
+        ),
+        AssemblerError::new(
+          AssemblerErrorKind::WrongOperandType {
+            expected: OperandKind::Address
+          },
+          5,
+          5,
+          "$1"
+        ),
+        AssemblerError::new(AssemblerErrorKind::RegisterOutOfRange, 6, 5, "$300"),
+      ]
+    );
+    assert_eq!(errors[0].to_string(), "1:8: expected register operand `#5`");
+  }
+
+  #[test]
+  fn test_assemble_huge_literals() {
+    let mut asm = Assembler::new();
+    let errors = asm
+      .assemble(
+        "ldi $0 #99999999999999999999
+.equ X 1 + -0x8000_0000_0000_0001
+9abc: hlt",
+      )
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(
+          AssemblerErrorKind::ImmediateOutOfRange,
+          1,
+          9,
+          "99999999999999999999"
+        ),
+        AssemblerError::new(
+          AssemblerErrorKind::ImmediateOutOfRange,
+          2,
+          13,
+          "0x8000_0000_0000_0001"
+        ),
+        AssemblerError::new(AssemblerErrorKind::ParseError, 3, 1, "9abc: hlt"),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_assemble_bad_directive_operands() {
+    let mut asm = Assembler::new();
+    let errors = asm.assemble("str: .asciiz 'a' 'b'\n.code #1").unwrap_err();
+    assert_eq!(errors[0].kind, AssemblerErrorKind::BadOperandCount);
+    assert_eq!(errors[1].kind, AssemblerErrorKind::BadOperandCount);
+  }
+
+  #[test]
+  fn test_assemble_ro_data() {
+    let mut asm = Assembler::new();
+    let test_string = ".data\nhello: .asciiz 'Hi'\n.code\nstart: prts @hello\njmp @start";
+    let program = asm.assemble(test_string).unwrap();
+    assert_eq!(program.len(), 64 + 3 + 8);
+    assert_eq!(&program[12..16], &[3, 0, 0, 0]);
+    assert_eq!(&program[64..67], b"Hi\0");
+    assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+    assert_eq!(asm.symbols.symbol_value("start"), Some(67));
+    assert_eq!(&program[67..75], &[19, 0, 0, 0, 6, 0, 67, 0]);
+  }
+
+  #[test]
+  fn test_assemble_entry_point() {
+    let mut asm = Assembler::new();
+    let program = asm.assemble("hlt\nmain: ld $0 #1").unwrap();
+    let header = PieHeader::from_bytes(&program).unwrap();
+    assert_eq!(header.code_offset, 64);
+    assert_eq!(header.code_length, 8);
+    assert_eq!(header.entry_point, 68);
+
+    let errors = Assembler::new()
+      .assemble(".data\nmain: .asciiz 'x'\n.code\nhlt")
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![AssemblerError::new(
+        AssemblerErrorKind::EntryPointNotCode,
+        2,
+        1,
+        "main:"
+      )]
+    );
+  }
+
+  #[test]
+  fn test_assemble_constants_and_expressions() {
+    let mut asm = Assembler::new();
+    let source = ".equ BUF_SIZE 16\n.set N #1\nld $0 #(BUF_SIZE * 2 + 1)\nld $1 #N\n\
+                  .set N (N + 1)\nld $2 #N\nldi $3 #hi(@table)\ntable: jmp @table+4\n\
+                  LAST: .equ #(@table + 4)";
+    let program = asm.assemble(source).unwrap();
+    assert_eq!(&program[64..68], &[1, 0, 0, 33]);
+    assert_eq!(&program[68..72], &[1, 1, 0, 1]);
+    assert_eq!(&program[72..76], &[1, 2, 0, 2]);
+    assert_eq!(&program[76..84], &[58, 3, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&program[84..88], &[6, 0, 88, 0]);
+    assert_eq!(asm.symbols.resolve("LAST"), Some(88));
+    assert_eq!(asm.symbols.symbol_value("BUF_SIZE"), None);
+    assert_eq!(asm.symbols.symbol_at(84).map(|s| s.name()), Some("table"));
+  }
+
+  #[test]
+  fn test_assemble_constant_errors() {
+    let mut asm = Assembler::new();
+    let source = ".equ BIG 70000\n.equ A MISSING + 1\n.equ BIG 1\nld $0 #BIG\nld $1 #(1 / 0)\nld $2 #UNDEFINED";
+    let errors = asm.assemble(source).unwrap_err();
+    let kinds: Vec<AssemblerErrorKind> = errors.iter().map(|e| e.kind).collect();
+    assert_eq!(
+      kinds,
+      vec![
+        AssemblerErrorKind::UnknownSymbol,
+        AssemblerErrorKind::DuplicateLabel,
+        AssemblerErrorKind::ImmediateOutOfRange,
+        AssemblerErrorKind::DivisionByZero,
+        AssemblerErrorKind::UnknownSymbol,
+      ]
+    );
+    assert_eq!((errors[3].column, errors[3].text.as_str()), (7, "#(1 / 0)"));
+    let errors = Assembler::new()
+      .assemble(".equ BUF 4\nld $0 #(BUF * 2 + UNKNOWN)\n.equ AB B")
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(
+          AssemblerErrorKind::UnknownSymbol,
+          2,
+          7,
+          "#(BUF * 2 + UNKNOWN)"
+        ),
+        AssemblerError::new(AssemblerErrorKind::UnknownSymbol, 3, 9, "B"),
+      ]
+    );
+
+    let source = ".set X 1\nld $0 #X\n.set X UNDEFINED\n.set X 1 / 0\n.set Y MISSING";
+    let errors = Assembler::new().assemble(source).unwrap_err();
+    let kinds: Vec<(AssemblerErrorKind, usize)> = errors.iter().map(|e| (e.kind, e.line)).collect();
+    assert_eq!(
+      kinds,
+      vec![
+        (AssemblerErrorKind::UnknownSymbol, 3),
+        (AssemblerErrorKind::DivisionByZero, 4),
+        (AssemblerErrorKind::UnknownSymbol, 5),
+      ]
+    );
+
+    let errors = Assembler::new()
+      .assemble("ld $0 #N\n.set N 1\nld $1 #N")
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![AssemblerError::new(
+        AssemblerErrorKind::UnknownSymbol,
+        1,
+        7,
+        "#N"
+      )]
+    );
+    let mut asm = Assembler::new();
+    asm
+      .assemble(".set N 1\n.equ M (N + 1)\n.set N 5\n.equ K N\nhlt")
+      .unwrap();
+    assert_eq!(
+      (asm.symbols.resolve("M"), asm.symbols.resolve("K")),
+      (Some(2), Some(5))
+    );
+
+    let errors = Assembler::new()
+      .assemble("start: .code\nhlt\nmsg: .data")
+      .unwrap_err();
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(AssemblerErrorKind::UnexpectedLabel, 1, 1, "start:"),
+        AssemblerError::new(AssemblerErrorKind::UnexpectedLabel, 3, 1, "msg:"),
+      ]
+    );
+
+    let errors = Assembler::new().assemble(".settings\nhlt").unwrap_err();
+    assert_eq!(
+      errors,
+      vec![AssemblerError::new(
+        AssemblerErrorKind::UnknownSection,
+        1,
+        1,
+        ".settings"
+      )]
+    );
+  }
 }
@@ -14,13 +14,14 @@ named!(pub opcode<CompleteStr, Token>,
 
 //----------------------------------------------------------------------------------------------
 
+#[cfg(test)]
 mod tests {
   use super::*;
 
   #[test]
   fn test_opcode_parse_load() {
     let result = opcode(CompleteStr("ld"));
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let (rest, token) = result.unwrap();
     assert_eq!(token, Token::Op { code: Opcode::LOAD });
     assert_eq!(rest, CompleteStr(""));
@@ -1,7 +1,8 @@
+use super::expression_parser::{atom, label_expression};
 use super::label_parser::label_usage;
-use super::register_parser::register;
-use nom::digit;
+use super::register_parser::{float_register, register};
 use nom::types::CompleteStr;
+use nom::{digit, ErrorKind, IResult};
 
 use super::Token;
 
@@ -9,9 +10,139 @@ named!(pub integer_operand<CompleteStr, Token>,
   ws!(
     do_parse!(
       tag!("#") >>
-      reg_num: digit >>
+      value: integer_literal >>
       (
-        Token::IntegerOperand {value: reg_num.parse::<i32>().unwrap()}
+        Token::IntegerOperand { value }
+      )
+    )
+  )
+);
+
+/// Error code of an integer literal too big for an `i64`. Parsing stops
+/// there, instead of trying the literal as something else.
+pub const LITERAL_OUT_OF_RANGE: u32 = 1;
+
+// Signed decimal, hexadecimal (`0x`), binary (`0b`) or octal (`0o`) integer,
+// with `_` allowed as a digit separator, or a character literal.
+named!(pub integer_literal<CompleteStr, i64>,
+  alt!(
+    char_literal |
+    do_parse!(
+      peek!(number_literal) >>
+      value: return_error!(
+        ErrorKind::Custom(LITERAL_OUT_OF_RANGE),
+        map_opt!(number_literal, parse_number)
+      ) >>
+      (value)
+    )
+  )
+);
+
+// Text of a number, sign and radix prefix included.
+named!(pub number_literal<CompleteStr, CompleteStr>,
+  recognize!(pair!(
+    opt!(one_of!("+-")),
+    alt!(
+      preceded!(tag_no_case!("0x"), call!(digits, 16)) |
+      preceded!(tag_no_case!("0b"), call!(digits, 2)) |
+      preceded!(tag_no_case!("0o"), call!(digits, 8)) |
+      call!(digits, 10)
+    )
+  ))
+);
+
+named_args!(digits(radix: u32)<CompleteStr, CompleteStr>,
+  recognize!(pair!(
+    take_while1!(|c: char| c.is_digit(radix)),
+    take_while!(|c: char| c.is_digit(radix) || c == '_')
+  ))
+);
+
+fn parse_number(text: CompleteStr) -> Option<i64> {
+  let (sign, magnitude) = match text.chars().next() {
+    Some(c) if c == '-' || c == '+' => (&text[..1], &text[1..]),
+    _ => ("", &text[..]),
+  };
+  let (radix, digits) = match magnitude.get(..2).map(str::to_ascii_lowercase) {
+    Some(prefix) if prefix == "0x" => (16, &magnitude[2..]),
+    Some(prefix) if prefix == "0b" => (2, &magnitude[2..]),
+    Some(prefix) if prefix == "0o" => (8, &magnitude[2..]),
+    _ => (10, magnitude),
+  };
+  i64::from_str_radix(&format!("{}{}", sign, digits.replace('_', "")), radix).ok()
+}
+
+// A single character between quotes, `\\`, `\'`, `\n`, `\t`, `\r` and `\0`
+// being the escaped ones.
+named!(char_literal<CompleteStr, i64>,
+  map!(
+    delimited!(
+      char!('\''),
+      alt!(
+        preceded!(char!('\\'), map!(one_of!("\\'ntr0"), unescape)) |
+        none_of!("\\'")
+      ),
+      char!('\'')
+    ),
+    |c: char| c as i64
+  )
+);
+
+fn unescape(c: char) -> char {
+  match c {
+    'n' => '\n',
+    't' => '\t',
+    'r' => '\r',
+    '0' => '\0',
+    _ => c,
+  }
+}
+
+// Exponent of a float literal, as in `1e-3`.
+named!(exponent<CompleteStr, CompleteStr>,
+  recognize!(tuple!(one_of!("eE"), opt!(one_of!("+-")), digit))
+);
+
+// Float literal, told apart from an integer by its decimal point or exponent.
+named!(pub float_operand<CompleteStr, Token>,
+  ws!(
+    do_parse!(
+      tag!("#") >>
+      value: map_res!(
+        recognize!(tuple!(
+          opt!(tag!("-")),
+          digit,
+          alt!(recognize!(tuple!(tag!("."), digit, opt!(exponent))) | exponent)
+        )),
+        |d: CompleteStr| d.parse::<f64>()
+      ) >>
+      (
+        Token::FloatOperand { value }
+      )
+    )
+  )
+);
+
+// Integer operand computed from symbols: `#NAME`, `#(expression)` or
+// `#hi(@label)`.
+named!(pub integer_expression<CompleteStr, Token>,
+  ws!(
+    do_parse!(
+      tag!("#") >>
+      expr: atom >>
+      (
+        Token::IntegerExpression { expr }
+      )
+    )
+  )
+);
+
+named!(pub label_offset<CompleteStr, Token>,
+  ws!(
+    do_parse!(
+      expr: label_expression >>
+      (
+        Token::LabelExpression { expr }
       )
     )
   )
@@ -19,13 +150,28 @@ named!(pub integer_operand<CompleteStr, Token>,
 
 named!(pub operand<CompleteStr, Token>,
   alt!(
+    float_operand |
     integer_operand |
+    integer_expression |
+    float_register |
     register |
+    label_offset |
     label_usage |
     irstring
   )
 );
 
+/// Runs `parser` and also returns the source text it consumed, without the
+/// surrounding whitespace, for diagnostics.
+pub fn with_source<'a, O>(
+  input: CompleteStr<'a>,
+  parser: impl Fn(CompleteStr<'a>) -> IResult<CompleteStr<'a>, O>,
+) -> IResult<CompleteStr<'a>, (O, String)> {
+  let (rest, output) = parser(input)?;
+  let source = input[..input.len() - rest.len()].trim();
+  Ok((rest, (output, source.to_string())))
+}
+
 named!(pub irstring <CompleteStr, Token>,
   do_parse!(
     tag!("'") >>
@@ -40,6 +186,8 @@ named!(pub irstring <CompleteStr, Token>,
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::assembler::expression_parser::Expr;
+
   #[test]
   fn test_parse_integer_operand() {
     let result = integer_operand(CompleteStr("#10"));
@@ -52,10 +200,79 @@ mod tests {
   #[test]
   fn test_parse_invalid_integer_operands() {
     let result = integer_operand(CompleteStr("10"));
-    assert!(!result.is_ok());
+    assert!(result.is_err());
 
     let result = integer_operand(CompleteStr("#a"));
-    assert!(!result.is_ok());
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_parse_integer_literals() {
+    for (text, value) in &[
+      ("#-1", -1),
+      ("#+7", 7),
+      ("#1_000_000", 1_000_000),
+      ("#0xFF", 255),
+      ("#-0x8000_0000", -0x8000_0000),
+      ("#0b1010", 10),
+      ("#0o17", 15),
+      ("#'A'", 65),
+      ("#'\\n'", 10),
+      ("#'\\''", 39),
+    ] {
+      assert_eq!(
+        integer_operand(CompleteStr(text)),
+        Ok((CompleteStr(""), Token::IntegerOperand { value: *value })),
+        "{}",
+        text
+      );
+    }
+    for text in &["#_1", "#0x", "#''", "#'ab'", "#99999999999999999999"] {
+      assert!(
+        integer_operand(CompleteStr(text)).map_or(true, |(rest, _)| !rest.is_empty()),
+        "{}",
+        text
+      );
+    }
+  }
+
+  #[test]
+  fn test_parse_expression_operands() {
+    let (_, token) = operand(CompleteStr("#(SIZE * 2 + 1)")).unwrap();
+    assert_eq!(token.to_string(), "#((SIZE*2)+1)");
+    let (_, token) = operand(CompleteStr("#hi(@table)")).unwrap();
+    assert_eq!(token.to_string(), "#hi(table)");
+    let (_, token) = operand(CompleteStr("#SIZE")).unwrap();
+    assert_eq!(
+      token,
+      Token::IntegerExpression {
+        expr: Expr::Symbol("SIZE".to_string())
+      }
+    );
+    let (rest, token) = operand(CompleteStr("@table+8 $1")).unwrap();
+    assert_eq!(token.to_string(), "@(table+8)");
+    assert_eq!(rest, CompleteStr("$1"));
+  }
+
+  #[test]
+  fn test_parse_float_operand() {
+    for (text, value) in &[
+      ("#3.25", 3.25),
+      ("#-0.5", -0.5),
+      ("#1e3", 1000.0),
+      ("#2.5E-1", 0.25),
+    ] {
+      assert_eq!(
+        float_operand(CompleteStr(text)),
+        Ok((CompleteStr(""), Token::FloatOperand { value: *value }))
+      );
+    }
+    assert!(float_operand(CompleteStr("#3")).is_err());
+    assert!(float_operand(CompleteStr("#3.")).is_err());
+    assert_eq!(
+      operand(CompleteStr("#3")),
+      Ok((CompleteStr(""), Token::IntegerOperand { value: 3 }))
+    );
   }
 
   #[test]
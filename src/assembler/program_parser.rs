@@ -1,67 +1,200 @@
 use super::instruction_parser::*;
-use super::SymbolTable;
+use super::operand_parser::{number_literal, LITERAL_OUT_OF_RANGE};
+use super::{AssemblerError, AssemblerErrorKind, SourceSpan, SymbolTable};
 use nom::types::CompleteStr;
+use nom::{Context, ErrorKind};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
   pub instructions: Vec<AsmInstruction>,
 }
 
-named!(pub program<CompleteStr, Program>,
-  do_parse!(
-    instructions: many1!(instruction) >>
-    (
-      Program {
-        instructions: instructions
+/// Parses every instruction in `source`, tagging each one with its position.
+/// Unparseable input is reported and skipped up to the end of its line, so a
+/// single pass finds every syntax error in the file.
+pub fn parse_program(source: &str) -> (Program, Vec<AssemblerError>) {
+  let source = &strip_comments(source);
+  let mut instructions = vec![];
+  let mut errors = vec![];
+  let mut rest = source.trim_start();
+
+  while !rest.is_empty() {
+    let offset = source.len() - rest.len();
+    match instruction(CompleteStr(rest)) {
+      Ok((remaining, mut ins)) if remaining.len() < rest.len() => {
+        let consumed = &rest[..rest.len() - remaining.len()];
+        ins.set_span(SourceSpan::new(source, offset, consumed.trim_end()));
+        instructions.push(ins);
+        rest = remaining.0;
+      }
+      failure => {
+        let line_end = rest.find('\n').unwrap_or(rest.len());
+        let span = SourceSpan::new(source, offset, rest[..line_end].trim_end());
+        errors.push(match failure {
+          Err(nom::Err::Failure(Context::Code(input, ErrorKind::Custom(LITERAL_OUT_OF_RANGE)))) => {
+            let literal = number_literal(input).map_or(input, |(_, text)| text);
+            let offset = source.len() - input.len();
+            SourceSpan::new(source, offset, &literal)
+              .error(AssemblerErrorKind::ImmediateOutOfRange, &literal)
+          }
+          _ => span.error(AssemblerErrorKind::ParseError, &span.text),
+        });
+        // Drop what was parsed from the start of the broken line, so it does
+        // not cause follow-up errors
+        instructions.retain(|i: &AsmInstruction| i.span().line != span.line);
+        rest = &rest[line_end..];
       }
-    )
-  )
-);
+    }
+    rest = rest.trim_start();
+  }
+
+  (Program { instructions }, errors)
+}
+
+/// Removes the `;` and `#!` comments running to the end of their line,
+/// leaving everything else where it was so positions stay accurate. Comment
+/// markers inside strings and character literals are kept.
+fn strip_comments(source: &str) -> String {
+  let mut stripped = String::with_capacity(source.len());
+  for line in source.split_inclusive('\n') {
+    let content = line.trim_end_matches(&['\r', '\n'][..]);
+    let mut end = content.len();
+    let mut in_string = false;
+    let mut in_char = false;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in content.char_indices() {
+      if in_char && escaped {
+        escaped = false;
+      } else if in_char && c == '\\' {
+        escaped = true;
+      } else if in_string || in_char {
+        if c == '\'' {
+          in_string = false;
+          in_char = false;
+        }
+      } else if c == '\'' {
+        // Only character literals, as in `#'a'`, have escapes
+        in_char = previous == '#';
+        in_string = !in_char;
+      } else if c == ';' || (previous == '#' && c == '!') {
+        end = if c == ';' { i } else { i - 1 };
+        break;
+      }
+      previous = c;
+    }
+    stripped.push_str(&content[..end]);
+    stripped.push_str(&line[content.len()..]);
+  }
+  stripped
+}
 
 impl Program {
-  pub fn to_bytes(&self, symbols: &SymbolTable) -> Vec<u8> {
+  pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, Vec<AssemblerError>> {
     let mut program: Vec<u8> = vec![];
+    let mut errors = vec![];
 
     for inst in &self.instructions {
-      program.append(&mut inst.to_bytes(symbols));
+      match inst.to_bytes(symbols) {
+        Ok(mut bytes) => program.append(&mut bytes),
+        Err(e) => errors.push(e),
+      }
     }
 
-    program
+    if errors.is_empty() {
+      Ok(program)
+    } else {
+      Err(errors)
+    }
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::assembler::{Symbol, SymbolType};
 
   #[test]
   fn test_parse_program() {
-    let result = program(CompleteStr("ld $0 #100\nld $1 #55"));
-    assert!(result.is_ok());
-    let (leftover, p) = result.unwrap();
-    assert_eq!(leftover, CompleteStr(""));
+    let (p, errors) = parse_program("ld $0 #100\nld $1 #55");
+    assert!(errors.is_empty());
     assert_eq!(p.instructions.len(), 2);
   }
 
   #[test]
   fn test_program_to_bytes() {
-    let result = program(CompleteStr("ld $1 #100"));
-    assert!(result.is_ok());
-    let (_, prg) = result.unwrap();
+    let (prg, errors) = parse_program("ld $1 #100");
+    assert!(errors.is_empty());
     let symbols = SymbolTable::new();
-    let bytes = prg.to_bytes(&symbols);
+    let bytes = prg.to_bytes(&symbols).unwrap();
     assert_eq!(bytes.len(), 4);
     assert_eq!(bytes, vec![1, 1, 0, 100]);
   }
 
   #[test]
   fn test_program_with_logical_op_to_bytes() {
-    let result = program(CompleteStr("eq $0 $1"));
-    assert!(result.is_ok());
-    let (_, prg) = result.unwrap();
+    let (prg, errors) = parse_program("eq $0 $1");
+    assert!(errors.is_empty());
     let symbols = SymbolTable::new();
-    let bytes = prg.to_bytes(&symbols);
+    let bytes = prg.to_bytes(&symbols).unwrap();
     assert_eq!(bytes.len(), 4);
     assert_eq!(bytes, vec![9, 0, 1, 0]);
   }
+
+  #[test]
+  fn test_parse_program_spans() {
+    let (p, errors) = parse_program("ld $0 #100\n  hlt\n");
+    assert!(errors.is_empty());
+    assert_eq!(p.instructions[0].span().line, 1);
+    assert_eq!(p.instructions[1].span().line, 2);
+    assert_eq!(p.instructions[1].span().column, 3);
+    assert_eq!(p.instructions[1].span().text, "hlt");
+  }
+
+  #[test]
+  fn test_parse_program_recovers_from_errors() {
+    let (p, errors) = parse_program("ld $0 #100\n%%% nonsense\nld $1 #5\n???");
+    assert_eq!(p.instructions.len(), 2);
+    assert_eq!(
+      errors,
+      vec![
+        AssemblerError::new(AssemblerErrorKind::ParseError, 2, 1, "%%% nonsense"),
+        AssemblerError::new(AssemblerErrorKind::ParseError, 4, 1, "???"),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_program_with_comments() {
+    let source = "#! iridium\n\n; setup\nLD $0 #1 ; init   \nloop: ; counts\n  Inc $0\n\
+                  ld $1 #';' #! semicolon\n.ASCIIZ 'a;b' ;\r\nhlt";
+    let (p, errors) = parse_program(source);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(p.instructions.len(), 5);
+    assert_eq!(p.instructions[0].span().text, "LD $0 #1");
+    assert_eq!(p.instructions[1].span().line, 5);
+    assert_eq!(p.instructions[2].span().text, "ld $1 #';'");
+    assert_eq!(p.instructions[4].span().line, 9);
+  }
+
+  #[test]
+  fn test_strip_comments() {
+    assert_eq!(
+      strip_comments("a ; b\n'x;y' #!z\n#'\\'' ; c"),
+      "a \n'x;y' \n#'\\'' "
+    );
+  }
+
+  #[test]
+  fn test_program_with_stack_ops_to_bytes() {
+    let (prg, errors) = parse_program("push $3\ncall @sub\nret\npop $3");
+    assert!(errors.is_empty());
+    let mut symbols = SymbolTable::new();
+    symbols.add_symbol(Symbol::new("sub".to_string(), 300, SymbolType::Label));
+    let bytes = prg.to_bytes(&symbols).unwrap();
+    assert_eq!(
+      bytes,
+      vec![22, 3, 0, 0, 20, 1, 44, 0, 21, 0, 0, 0, 23, 3, 0, 0]
+    );
+  }
 }
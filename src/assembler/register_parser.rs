@@ -3,15 +3,31 @@ use nom::types::CompleteStr;
 
 use crate::assembler::Token;
 
+// Register numbers too big for a `u32` saturate, so the assembler reports
+// them as out of range like any other register past the last one.
+named!(register_number<CompleteStr, u32>,
+  map!(digit, |d| d.parse().unwrap_or(u32::MAX))
+);
+
 named!(pub register <CompleteStr, Token>,
   ws!(
     do_parse!(
       tag!("$") >>
-      reg_num: digit >>
+      reg_num: register_number >>
       (
-        Token::Register {
-          reg_num: reg_num.parse::<u8>().unwrap()
-        }
+        Token::Register { reg_num }
+      )
+    )
+  )
+);
+
+named!(pub float_register <CompleteStr, Token>,
+  ws!(
+    do_parse!(
+      tag_no_case!("$f") >>
+      reg_num: register_number >>
+      (
+        Token::FloatRegister { reg_num }
       )
     )
   )
@@ -24,10 +40,28 @@ mod tests {
   #[test]
   fn test_registers_parsing() {
     let result = register(CompleteStr("$0"));
-    assert_eq!(result.is_ok(), true);
+    assert!(result.is_ok());
     let result = register(CompleteStr("0"));
-    assert_eq!(result.is_ok(), false);
+    assert!(result.is_err());
     let result = register(CompleteStr("$a"));
-    assert_eq!(result.is_ok(), false);
+    assert!(result.is_err());
+    let result = register(CompleteStr("$99999999999"));
+    assert_eq!(
+      result,
+      Ok((CompleteStr(""), Token::Register { reg_num: u32::MAX }))
+    );
+  }
+
+  #[test]
+  fn test_float_registers_parsing() {
+    let result = float_register(CompleteStr("$f3"));
+    assert_eq!(
+      result,
+      Ok((CompleteStr(""), Token::FloatRegister { reg_num: 3 }))
+    );
+    let result = float_register(CompleteStr("$3"));
+    assert!(result.is_err());
+    let result = register(CompleteStr("$f3"));
+    assert!(result.is_err());
   }
 }
@@ -0,0 +1,316 @@
+use super::assembler::ENTRY_POINT_LABEL;
+use super::instruction::{Opcode, OperandKind};
+use super::pie::{PieHeader, PieHeaderError};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DisassemblerError {
+  InvalidHeader(PieHeaderError),
+  IllegalOpcode { offset: usize, byte: u8 },
+  TruncatedInstruction { offset: usize },
+  NonZeroPadding { offset: usize },
+  UnrepresentableString { offset: usize },
+  MisalignedEntryPoint { offset: usize },
+}
+
+impl fmt::Display for DisassemblerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DisassemblerError::InvalidHeader(e) => write!(f, "invalid program header: {}", e),
+      DisassemblerError::IllegalOpcode { offset, byte } => {
+        write!(f, "illegal opcode {} at {}", byte, offset)
+      }
+      DisassemblerError::TruncatedInstruction { offset } => {
+        write!(f, "truncated instruction at {}", offset)
+      }
+      DisassemblerError::NonZeroPadding { offset } => {
+        write!(f, "instruction at {} has non-zero padding", offset)
+      }
+      DisassemblerError::UnrepresentableString { offset } => write!(
+        f,
+        "read-only data at {} is not a valid string constant",
+        offset
+      ),
+      DisassemblerError::MisalignedEntryPoint { offset } => {
+        write!(f, "entry point {} is not at an instruction", offset)
+      }
+    }
+  }
+}
+
+impl Error for DisassemblerError {}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operand {
+  Register(u8),
+  FloatRegister(u8),
+  Integer(i32),
+  /// Absolute program offset, target of a jump or call.
+  Address(u16),
+  /// Offset of a string in the read-only data.
+  String(u16),
+  Float(f64),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecodedInstruction {
+  pub offset: usize,
+  pub opcode: Opcode,
+  pub operands: Vec<Operand>,
+}
+
+/// Decodes the instruction starting at `offset` in `program`.
+pub fn decode(program: &[u8], offset: usize) -> Result<DecodedInstruction, DisassemblerError> {
+  let byte = *program
+    .get(offset)
+    .ok_or(DisassemblerError::TruncatedInstruction { offset })?;
+  let opcode = Opcode::from(byte);
+  if opcode == Opcode::IGL {
+    return Err(DisassemblerError::IllegalOpcode { offset, byte });
+  }
+  let bytes = program
+    .get(offset..offset + opcode.width())
+    .ok_or(DisassemblerError::TruncatedInstruction { offset })?;
+
+  let mut operands = vec![];
+  let mut i = 1;
+  for kind in opcode.operands() {
+    let operand = match kind {
+      OperandKind::Register => Operand::Register(bytes[i]),
+      OperandKind::FloatRegister => Operand::FloatRegister(bytes[i]),
+      OperandKind::Integer8 => Operand::Integer(bytes[i] as i32),
+      OperandKind::Integer16 => Operand::Integer(read_u16(&bytes[i..]) as i32),
+      OperandKind::Integer32 => Operand::Integer(i32::from_be_bytes([
+        bytes[i],
+        bytes[i + 1],
+        bytes[i + 2],
+        bytes[i + 3],
+      ])),
+      OperandKind::Address => Operand::Address(read_u16(&bytes[i..])),
+      OperandKind::String => Operand::String(read_u16(&bytes[i..])),
+      OperandKind::Float => {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[i..i + 8]);
+        Operand::Float(f64::from_be_bytes(value))
+      }
+    };
+    i += kind.width();
+    operands.push(operand);
+  }
+  if bytes[i..].iter().any(|b| *b != 0) {
+    return Err(DisassemblerError::NonZeroPadding { offset });
+  }
+
+  Ok(DecodedInstruction {
+    offset,
+    opcode,
+    operands,
+  })
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+  ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+/// Disassembles the single instruction at `offset`, with every operand
+/// written as a plain number.
+pub fn disassemble_instruction(program: &[u8], offset: usize) -> Result<String, DisassemblerError> {
+  let instruction = decode(program, offset)?;
+  Ok(format_instruction(
+    &instruction,
+    &BTreeMap::new(),
+    &BTreeMap::new(),
+  ))
+}
+
+/// Disassembles a PIE image into assembler source that assembles back into
+/// the same image. Jump targets and strings get generated labels.
+pub fn disassemble(image: &[u8]) -> Result<String, DisassemblerError> {
+  let header = PieHeader::from_bytes(image).map_err(DisassemblerError::InvalidHeader)?;
+  let code_range = header.code_range();
+  let ro_range = header.ro_range();
+
+  let strings = split_strings(&image[ro_range])?;
+  let mut instructions = vec![];
+  let mut offset = code_range.start;
+  while offset < code_range.end {
+    let instruction = decode(image, offset)?;
+    offset += instruction.opcode.width();
+    instructions.push(instruction);
+  }
+
+  let is_instruction = |target: usize| {
+    instructions
+      .binary_search_by_key(&target, |instruction| instruction.offset)
+      .is_ok()
+  };
+  let mut labels = BTreeMap::new();
+  let entry_point = header.entry_point as usize;
+  if entry_point != code_range.start {
+    if !is_instruction(entry_point) {
+      return Err(DisassemblerError::MisalignedEntryPoint {
+        offset: entry_point,
+      });
+    }
+    labels.insert(entry_point, ENTRY_POINT_LABEL.to_string());
+  }
+  for instruction in &instructions {
+    for operand in &instruction.operands {
+      if let Operand::Address(target) = operand {
+        let target = *target as usize;
+        if is_instruction(target) && !labels.contains_key(&target) {
+          labels.insert(target, format!("l{}", target));
+        }
+      }
+    }
+  }
+  let string_labels: BTreeMap<usize, String> = strings
+    .keys()
+    .map(|offset| (*offset, format!("str{}", offset)))
+    .collect();
+
+  let mut source = String::from(".data\n");
+  for (offset, text) in &strings {
+    source.push_str(&format!("{}: .asciiz '{}'\n", string_labels[offset], text));
+  }
+  source.push_str(".code\n");
+  for instruction in &instructions {
+    if let Some(label) = labels.get(&instruction.offset) {
+      source.push_str(&format!("{}: ", label));
+    }
+    source.push_str(&format_instruction(instruction, &labels, &string_labels));
+    source.push('\n');
+  }
+  Ok(source)
+}
+
+/// Splits the read-only data into its NUL-terminated strings, keyed by offset.
+fn split_strings(ro_data: &[u8]) -> Result<BTreeMap<usize, String>, DisassemblerError> {
+  let mut strings = BTreeMap::new();
+  let mut start = 0;
+  while start < ro_data.len() {
+    let error = DisassemblerError::UnrepresentableString { offset: start };
+    let length = ro_data[start..]
+      .iter()
+      .position(|b| *b == 0)
+      .ok_or_else(|| error.clone())?;
+    let text = std::str::from_utf8(&ro_data[start..start + length]).map_err(|_| error.clone())?;
+    if text.contains('\'') {
+      return Err(error);
+    }
+    strings.insert(start, text.to_string());
+    start += length + 1;
+  }
+  Ok(strings)
+}
+
+fn format_instruction(
+  instruction: &DecodedInstruction,
+  labels: &BTreeMap<usize, String>,
+  string_labels: &BTreeMap<usize, String>,
+) -> String {
+  let mut text = instruction.opcode.mnemonic().to_string();
+  for operand in &instruction.operands {
+    let formatted = match operand {
+      Operand::Register(r) => format!("${}", r),
+      Operand::FloatRegister(r) => format!("$f{}", r),
+      Operand::Integer(value) => format!("#{}", value),
+      Operand::Float(value) => format!("#{:?}", value),
+      Operand::Address(target) => match labels.get(&(*target as usize)) {
+        Some(label) => format!("@{}", label),
+        None => format!("#{}", target),
+      },
+      Operand::String(offset) => match string_labels.get(&(*offset as usize)) {
+        Some(label) => format!("@{}", label),
+        None => format!("#{}", offset),
+      },
+    };
+    text.push(' ');
+    text.push_str(&formatted);
+  }
+  text
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::Assembler;
+
+  fn assert_round_trip(source: &str) -> String {
+    let image = Assembler::new().assemble(source).unwrap();
+    let text = disassemble(&image).unwrap();
+    let reassembled = Assembler::new().assemble(&text).unwrap();
+    assert_eq!(image, reassembled, "{}", text);
+    text
+  }
+
+  #[test]
+  fn test_disassemble_instruction() {
+    let program = vec![1, 0, 2, 246, 25, 3, 4, 8, 21, 0, 0, 0];
+    assert_eq!(disassemble_instruction(&program, 0).unwrap(), "ld $0 #758");
+    assert_eq!(
+      disassemble_instruction(&program, 4).unwrap(),
+      "stb $3 $4 #8"
+    );
+    assert_eq!(disassemble_instruction(&program, 8).unwrap(), "ret");
+  }
+
+  #[test]
+  fn test_decode_errors() {
+    let program = vec![200, 0, 0, 0, 0, 1, 0, 0, 1, 0];
+    assert_eq!(
+      decode(&program, 0),
+      Err(DisassemblerError::IllegalOpcode {
+        offset: 0,
+        byte: 200
+      })
+    );
+    assert_eq!(
+      decode(&program, 4),
+      Err(DisassemblerError::NonZeroPadding { offset: 4 })
+    );
+    assert_eq!(
+      decode(&program, 8),
+      Err(DisassemblerError::TruncatedInstruction { offset: 8 })
+    );
+  }
+
+  #[test]
+  fn test_disassemble_labels_and_strings() {
+    let source = ".data\nhello: .asciiz 'Hello'\n.code\nmain: prts @hello\nloop: inc $0\njmp @loop";
+    let text = assert_round_trip(source);
+    assert_eq!(
+      text,
+      ".data\nstr0: .asciiz 'Hello'\n.code\nprts @str0\nl74: inc $0\njmp @l74\n"
+    );
+  }
+
+  #[test]
+  fn test_round_trip_programs() {
+    assert_round_trip(include_str!("../test_code/test.asm"));
+    assert_round_trip(include_str!("../test_code/test_string.asm"));
+    assert_round_trip("ld $0 #5\nmain: call @sub\nhlt\nsub: push $1\nldw $1 $2 #4\npop $1\nret");
+    assert_round_trip("loop: dec $0\ncmp $0 $1\njgeu @loop\njo @loop\nhlt");
+    assert_round_trip("and $0 $1 $2\nor $3 $4 $5\nxor $0 $0 $0\nnot $1 $2\nshl $0 $1 $0\nshr $0 $1 $0\nsar $0 $1 $0\nmod $6 $7 $8");
+    assert_round_trip("ldf $f0 #-2.5\nldf $f1 #1e300\nldf $f31 #3\naddf $f0 $f1 $f2\nsubf $f0 $f1 $f2\nmulf $f0 $f1 $f2\ndivf $f0 $f1 $f2\ncmpf $f0 $f1\nitof $3 $f3\nftoi $f3 $3");
+    assert_round_trip("ldi $0 #2147483647\nldi $1 #0\nmov $2 $0\nhlt");
+    assert_round_trip("ldi $0 #-0x8000_0000\nldi $1 #0xffff_ffff\nld $2 #'A'\nldi $3 #-1");
+    assert_round_trip(".data\na: .asciiz ''\nb: .asciiz 'x y'\n.code\nprts @b\nprts @a\njmp #7");
+  }
+
+  #[test]
+  fn test_disassemble_rejects_unrepresentable_strings() {
+    let mut image = Assembler::new()
+      .assemble(".data\ns: .asciiz 'ab'\n.code\nhlt")
+      .unwrap();
+    image[65] = b'\'';
+    assert_eq!(
+      disassemble(&image),
+      Err(DisassemblerError::UnrepresentableString { offset: 0 })
+    );
+  }
+}
@@ -1,28 +1,195 @@
 use nom::types::CompleteStr;
+use std::fmt;
 
+/// Every instruction is encoded in a whole number of words of this length.
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// Number of general purpose registers, `$0` to `$31`.
+pub const REGISTER_COUNT: usize = 32;
+
+/// Encoding of an instruction operand.
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum Opcode {
-  HLT,
-  LOAD,
-  ADD,
-  SUB,
-  MUL,
-  DIV,
-  JMP,
-  JMPF,
-  JMPB,
-  EQ,
-  NEQ,
-  GT,
-  LT,
-  GTE,
-  LTE,
-  JEQ,
-  ALOC,
-  INC,
-  DEC,
-  PRTS,
-  IGL,
+pub enum OperandKind {
+  /// Register number, one byte.
+  Register,
+  /// Floating point register number, one byte.
+  FloatRegister,
+  /// Unsigned immediate, one byte.
+  Integer8,
+  /// Unsigned immediate, two big-endian bytes.
+  Integer16,
+  /// Signed immediate, four big-endian bytes. Values up to `u32::MAX` are
+  /// accepted and stored as their bit pattern.
+  Integer32,
+  /// Absolute program offset, two big-endian bytes.
+  Address,
+  /// Offset of a string in the read-only data, two big-endian bytes.
+  String,
+  /// Floating point immediate, the eight big-endian bytes of an `f64`.
+  Float,
+}
+
+impl OperandKind {
+  /// Number of bytes the operand takes in the encoded instruction.
+  pub fn width(&self) -> usize {
+    match self {
+      OperandKind::Register | OperandKind::FloatRegister | OperandKind::Integer8 => 1,
+      OperandKind::Integer16 | OperandKind::Address | OperandKind::String => 2,
+      OperandKind::Integer32 => 4,
+      OperandKind::Float => 8,
+    }
+  }
+
+  /// Largest integer value that can be encoded in the operand.
+  pub fn max_value(&self) -> u32 {
+    match self {
+      OperandKind::Register | OperandKind::FloatRegister => REGISTER_COUNT as u32 - 1,
+      OperandKind::Integer8 => u8::MAX as u32,
+      OperandKind::Integer32 => u32::MAX,
+      _ => u16::MAX as u32,
+    }
+  }
+
+  /// Smallest integer value that can be encoded in the operand.
+  pub fn min_value(&self) -> i64 {
+    match self {
+      OperandKind::Integer32 => i32::MIN as i64,
+      _ => 0,
+    }
+  }
+}
+
+impl fmt::Display for OperandKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match self {
+      OperandKind::Register => "register",
+      OperandKind::FloatRegister => "float register",
+      OperandKind::Integer8 => "8-bit immediate",
+      OperandKind::Integer16 => "16-bit immediate",
+      OperandKind::Integer32 => "32-bit immediate",
+      OperandKind::Address => "label or address",
+      OperandKind::String => "string label or offset",
+      OperandKind::Float => "float immediate",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+/// Static description of an opcode, the single source used to parse, encode,
+/// decode and validate instructions.
+#[derive(Debug, PartialEq)]
+pub struct OpcodeInfo {
+  pub opcode: Opcode,
+  pub code: u8,
+  pub mnemonic: &'static str,
+  pub operands: &'static [OperandKind],
+}
+
+impl OpcodeInfo {
+  /// Encoded width of the instruction: the opcode byte and its operands,
+  /// padded to whole instruction words.
+  pub fn width(&self) -> usize {
+    let used: usize = 1 + self.operands.iter().map(|o| o.width()).sum::<usize>();
+    used.div_ceil(INSTRUCTION_LENGTH) * INSTRUCTION_LENGTH
+  }
+}
+
+macro_rules! opcodes {
+  ($($opcode:ident = $code:expr, $mnemonic:expr, [$($operand:ident),*];)*) => {
+    #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+    pub enum Opcode {
+      $($opcode,)*
+      IGL,
+    }
+
+    /// Every opcode in declaration order, so an `Opcode` indexes its entry.
+    pub static OPCODES: &[OpcodeInfo] = OPCODE_TABLE;
+
+    const OPCODE_TABLE: &[OpcodeInfo] = &[
+      $(OpcodeInfo {
+        opcode: Opcode::$opcode,
+        code: $code,
+        mnemonic: $mnemonic,
+        operands: &[$(OperandKind::$operand),*],
+      },)*
+    ];
+  };
+}
+
+/// Table entries indexed by opcode byte, for decoding.
+static OPCODES_BY_CODE: [Option<&OpcodeInfo>; 256] = index_by_code(OPCODE_TABLE);
+
+const fn index_by_code(table: &'static [OpcodeInfo]) -> [Option<&'static OpcodeInfo>; 256] {
+  let mut index = [None; 256];
+  let mut i = 0;
+  while i < table.len() {
+    index[table[i].code as usize] = Some(&table[i]);
+    i += 1;
+  }
+  index
+}
+
+opcodes! {
+  HLT = 0, "hlt", [];
+  LOAD = 1, "ld", [Register, Integer16];
+  ADD = 2, "add", [Register, Register, Register];
+  SUB = 3, "sub", [Register, Register, Register];
+  MUL = 4, "mul", [Register, Register, Register];
+  DIV = 5, "div", [Register, Register, Register];
+  JMP = 6, "jmp", [Address];
+  JMPF = 7, "jmpf", [Register];
+  JMPB = 8, "jmpb", [Register];
+  EQ = 9, "eq", [Register, Register];
+  NEQ = 10, "neq", [Register, Register];
+  GT = 11, "gt", [Register, Register];
+  LT = 12, "lt", [Register, Register];
+  GTE = 13, "gte", [Register, Register];
+  LTE = 14, "lte", [Register, Register];
+  JEQ = 15, "jeq", [Address];
+  ALOC = 16, "aloc", [Register];
+  INC = 17, "inc", [Register];
+  DEC = 18, "dec", [Register];
+  PRTS = 19, "prts", [String];
+  CALL = 20, "call", [Address];
+  RET = 21, "ret", [];
+  PUSH = 22, "push", [Register];
+  POP = 23, "pop", [Register];
+  LDB = 24, "ldb", [Register, Register, Integer8];
+  STB = 25, "stb", [Register, Register, Integer8];
+  LDW = 26, "ldw", [Register, Register, Integer8];
+  STW = 27, "stw", [Register, Register, Integer8];
+  FREE = 28, "free", [Register];
+  SYSCALL = 29, "syscall", [Integer16];
+  CMP = 30, "cmp", [Register, Register];
+  JNE = 31, "jne", [Address];
+  JGT = 32, "jgt", [Address];
+  JLT = 33, "jlt", [Address];
+  JGE = 34, "jge", [Address];
+  JLE = 35, "jle", [Address];
+  JGTU = 36, "jgtu", [Address];
+  JLTU = 37, "jltu", [Address];
+  JGEU = 38, "jgeu", [Address];
+  JLEU = 39, "jleu", [Address];
+  JC = 40, "jc", [Address];
+  JO = 41, "jo", [Address];
+  AND = 42, "and", [Register, Register, Register];
+  OR = 43, "or", [Register, Register, Register];
+  XOR = 44, "xor", [Register, Register, Register];
+  NOT = 45, "not", [Register, Register];
+  SHL = 46, "shl", [Register, Register, Register];
+  SHR = 47, "shr", [Register, Register, Register];
+  SAR = 48, "sar", [Register, Register, Register];
+  MOD = 49, "mod", [Register, Register, Register];
+  LOADF = 50, "ldf", [FloatRegister, Float];
+  ADDF = 51, "addf", [FloatRegister, FloatRegister, FloatRegister];
+  SUBF = 52, "subf", [FloatRegister, FloatRegister, FloatRegister];
+  MULF = 53, "mulf", [FloatRegister, FloatRegister, FloatRegister];
+  DIVF = 54, "divf", [FloatRegister, FloatRegister, FloatRegister];
+  CMPF = 55, "cmpf", [FloatRegister, FloatRegister];
+  ITOF = 56, "itof", [Register, FloatRegister];
+  FTOI = 57, "ftoi", [FloatRegister, Register];
+  LDI = 58, "ldi", [Register, Integer32];
+  MOV = 59, "mov", [Register, Register];
 }
 
 pub struct Instruction {
@@ -31,63 +198,60 @@ pub struct Instruction {
 
 impl<'a> From<CompleteStr<'a>> for Opcode {
   fn from(v: CompleteStr<'a>) -> Self {
-    match v {
-      CompleteStr("hlt") => Opcode::HLT,
-      CompleteStr("ld") => Opcode::LOAD,
-      CompleteStr("add") => Opcode::ADD,
-      CompleteStr("sub") => Opcode::SUB,
-      CompleteStr("mul") => Opcode::MUL,
-      CompleteStr("div") => Opcode::DIV,
-      CompleteStr("jmp") => Opcode::JMP,
-      CompleteStr("jmpf") => Opcode::JMPF,
-      CompleteStr("jmpb") => Opcode::JMPB,
-      CompleteStr("eq") => Opcode::EQ,
-      CompleteStr("neq") => Opcode::NEQ,
-      CompleteStr("gt") => Opcode::GT,
-      CompleteStr("lt") => Opcode::LT,
-      CompleteStr("gte") => Opcode::GTE,
-      CompleteStr("lte") => Opcode::LTE,
-      CompleteStr("jeq") => Opcode::JEQ,
-      CompleteStr("aloc") => Opcode::ALOC,
-      CompleteStr("inc") => Opcode::INC,
-      CompleteStr("dec") => Opcode::DEC,
-      CompleteStr("prts") => Opcode::PRTS,
-      _ => Opcode::IGL,
-    }
+    Opcode::from_mnemonic(v.0).unwrap_or(Opcode::IGL)
+  }
+}
+
+impl Opcode {
+  /// Looks up an opcode by its mnemonic, in any case.
+  pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+    OPCODES
+      .iter()
+      .find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+      .map(|info| info.opcode)
+  }
+
+  /// Table entry of the opcode, `None` for `IGL`.
+  pub fn info(&self) -> Option<&'static OpcodeInfo> {
+    OPCODES.get(*self as usize)
+  }
+
+  /// Assembler mnemonic of the opcode, the inverse of parsing it.
+  pub fn mnemonic(&self) -> &'static str {
+    self.info().map_or("igl", |info| info.mnemonic)
+  }
+
+  pub fn operands(&self) -> &'static [OperandKind] {
+    self.info().map_or(&[], |info| info.operands)
+  }
+
+  /// Encoded width of the instruction in bytes.
+  pub fn width(&self) -> usize {
+    self.info().map_or(INSTRUCTION_LENGTH, |info| info.width())
   }
 }
 
 impl Instruction {
   pub fn new(opcode: Opcode) -> Instruction {
-    Instruction { opcode: opcode }
+    Instruction { opcode }
+  }
+
+  pub fn opcode(&self) -> Opcode {
+    self.opcode
   }
 }
 
 impl From<u8> for Opcode {
   fn from(v: u8) -> Self {
-    match v {
-      0 => Opcode::HLT,
-      1 => Opcode::LOAD,
-      2 => Opcode::ADD,
-      3 => Opcode::SUB,
-      4 => Opcode::MUL,
-      5 => Opcode::DIV,
-      6 => Opcode::JMP,
-      7 => Opcode::JMPF,
-      8 => Opcode::JMPB,
-      9 => Opcode::EQ,
-      10 => Opcode::NEQ,
-      11 => Opcode::GT,
-      12 => Opcode::LT,
-      13 => Opcode::GTE,
-      14 => Opcode::LTE,
-      15 => Opcode::JEQ,
-      16 => Opcode::ALOC,
-      17 => Opcode::INC,
-      18 => Opcode::DEC,
-      19 => Opcode::PRTS,
-      _ => Opcode::IGL,
-    }
+    OPCODES_BY_CODE[v as usize].map_or(Opcode::IGL, |info| info.opcode)
+  }
+}
+
+impl From<Opcode> for u8 {
+  /// Numeric code of the opcode. `IGL` has none and encodes as 255, which
+  /// never decodes to a valid opcode.
+  fn from(opcode: Opcode) -> u8 {
+    opcode.info().map_or(u8::MAX, |info| info.code)
   }
 }
 
@@ -121,9 +285,55 @@ mod tests {
     assert_eq!(op, Opcode::JMP);
   }
 
+  #[test]
+  fn test_mixed_case_opcode_from_string() {
+    assert_eq!(Opcode::from(CompleteStr("LD")), Opcode::LOAD);
+    assert_eq!(Opcode::from(CompleteStr("Jmp")), Opcode::JMP);
+  }
+
   #[test]
   fn test_parse_invalid_opcode_from_string() {
     let op = Opcode::from(CompleteStr("invalid one"));
     assert_eq!(op, Opcode::IGL);
   }
+
+  #[test]
+  fn test_stack_opcodes() {
+    assert_eq!(Opcode::from(CompleteStr("call")), Opcode::CALL);
+    assert_eq!(Opcode::from(CompleteStr("ret")), Opcode::RET);
+    assert_eq!(Opcode::from(22), Opcode::PUSH);
+    assert_eq!(Opcode::from(23), Opcode::POP);
+  }
+
+  #[test]
+  fn test_mnemonic_round_trip() {
+    for info in OPCODES {
+      let op = Opcode::from(info.code);
+      assert_eq!(Opcode::from(CompleteStr(op.mnemonic())), op);
+      assert_eq!(u8::from(op), info.code);
+      assert_eq!(op.info(), Some(info));
+    }
+    assert_eq!(Opcode::IGL.info(), None);
+    assert_eq!(Opcode::from(u8::MAX), Opcode::IGL);
+  }
+
+  #[test]
+  fn test_opcode_table_is_consistent() {
+    for (i, info) in OPCODES.iter().enumerate() {
+      assert!(OPCODES[i + 1..]
+        .iter()
+        .all(|other| other.code != info.code && other.mnemonic != info.mnemonic));
+      assert_eq!(info.width() % INSTRUCTION_LENGTH, 0);
+    }
+    assert_eq!(Opcode::IGL.info(), None);
+    assert_eq!(Opcode::from(200), Opcode::IGL);
+  }
+
+  #[test]
+  fn test_opcode_widths() {
+    assert_eq!(Opcode::HLT.width(), 4);
+    assert_eq!(Opcode::LOAD.width(), 4);
+    assert_eq!(Opcode::LDW.width(), 4);
+    assert_eq!(Opcode::ADD.operands().len(), 3);
+  }
 }
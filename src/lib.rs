@@ -0,0 +1,27 @@
+//! Iridium language VM: an assembler producing PIE images and the virtual
+//! machine that runs them.
+//!
+//! ```
+//! use iridium_vm::{Assembler, ExitReason, VM};
+//!
+//! let program = Assembler::new().assemble("ld $0 #42\nhlt").unwrap();
+//! let mut vm = VM::new();
+//! vm.program = program;
+//! assert_eq!(vm.run(), Ok(ExitReason::Halted));
+//! assert_eq!(vm.get_registers()[0], 42);
+//! ```
+
+#[macro_use]
+extern crate nom;
+
+pub mod assembler;
+pub mod disassembler;
+pub mod instruction;
+pub mod pie;
+pub mod vm;
+
+pub use assembler::{Assembler, AssemblerError, AssemblerErrorKind};
+pub use disassembler::{disassemble, DisassemblerError};
+pub use instruction::Opcode;
+pub use pie::{PieHeader, PieHeaderError};
+pub use vm::{ExitReason, Flags, RunLimits, Snapshot, SnapshotError, StepResult, VmError, VM};
@@ -1,42 +1,99 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufWriter;
 use std::path::Path;
-
-#[macro_use]
-extern crate nom;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate clap;
 
-use clap::App;
+use clap::{App, ArgMatches};
+use iridium_vm::vm::{TraceFilter, Tracer};
+use iridium_vm::{Assembler, ExitReason, Opcode, RunLimits, Snapshot, VM};
 
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+mod repl;
 
 fn main() {
+  env_logger::init();
   let yaml = load_yaml!("cli.yml");
   let matches = App::from_yaml(yaml).get_matches();
+  if let Some(debug_matches) = matches.subcommand_matches("debug") {
+    let mut repl = repl::Repl::new();
+    repl.debug_file(debug_matches.value_of("INPUT_FILE").unwrap());
+    repl.run();
+    return;
+  }
   let target_file = matches.value_of("INPUT_FILE");
-  match target_file {
+  let resume_file = matches.value_of("resume");
+  match target_file.or(resume_file) {
     Some(filename) => {
-      let program = read_file(filename);
-      let mut asm = assembler::Assembler::new();
-      let mut vm = vm::VM::new();
-      let program = asm.assemble(&program);
-      match program {
-        Some(p) => {
-          vm.program = p;
-          vm.run();
-          print_stars();
-          println!("Finished running {}. VM status: {:?}", filename, vm);
-          println!("Symbols table: {:?}", asm.symbols);
-          print_stars();
-          std::process::exit(0);
+      let mut asm = Assembler::new();
+      let mut vm = VM::new();
+      vm.register_std_host_fns();
+      vm.set_trap_on_overflow(matches.is_present("trap-overflow"));
+      // When resuming, the source is only needed for its symbols
+      if let Some(source_file) = target_file {
+        let program = match asm.assemble(&read_file(source_file)) {
+          Ok(p) => p,
+          Err(errors) => {
+            for e in errors {
+              println!("{}:{}", source_file, e);
+            }
+            std::process::exit(1);
+          }
+        };
+        vm.program = program;
+      }
+      set_tracer(&mut vm, &matches);
+      if matches.is_present("profile") || matches.is_present("profile-folded") {
+        vm.enable_profiling();
+      }
+      let limits = RunLimits {
+        max_instructions: parse_arg(&matches, "max-instructions"),
+        max_heap_bytes: parse_arg(&matches, "max-heap"),
+        deadline: parse_arg(&matches, "timeout")
+          .map(|ms| Instant::now() + Duration::from_millis(ms)),
+      };
+      let result = match resume_file {
+        Some(path) => {
+          vm.restore(read_snapshot(path));
+          vm.resume(limits)
         }
-        None => {}
+        None => vm.run_with_limits(limits),
       };
+      if let Some(path) = matches.value_of("snapshot") {
+        write_snapshot(&vm, path);
+      }
+      if let Some(mut tracer) = vm.take_tracer() {
+        if let Err(e) = tracer.flush() {
+          println!("Unable to write trace: {}", e);
+        }
+      }
+      print_stars();
+      match &result {
+        Ok(reason) => println!("Finished running {} ({:?})", filename, reason),
+        Err(e) => println!("Error running {}: {}", filename, e),
+      }
+      println!("VM status: {:?}", vm);
+      println!("Symbols table: {:?}", asm.symbols);
+      if let Some(profile) = vm.take_profile() {
+        if matches.is_present("profile") {
+          print_stars();
+          print!("{}", profile.report(&asm.symbols));
+        }
+        if let Some(path) = matches.value_of("profile-folded") {
+          if let Err(e) = std::fs::write(path, profile.folded_stacks(&asm.symbols)) {
+            println!("Unable to write {}: {}", path, e);
+          }
+        }
+      }
+      print_stars();
+      std::process::exit(match result {
+        Ok(ExitReason::Halted) | Ok(ExitReason::EndOfProgram) => 0,
+        Ok(_) => 2,
+        Err(_) => 1,
+      });
     }
     None => {
       start_repl();
@@ -45,7 +102,7 @@ fn main() {
 }
 
 fn start_repl() {
-  let mut repl = repl::REPL::new();
+  let mut repl = repl::Repl::new();
   repl.run();
 }
 
@@ -55,9 +112,7 @@ fn read_file(tmp: &str) -> String {
     Ok(mut fh) => {
       let mut contents = String::new();
       match fh.read_to_string(&mut contents) {
-        Ok(_) => {
-          return contents;
-        }
+        Ok(_) => contents,
         Err(e) => {
           println!("Error reading file: {:?}", e);
           std::process::exit(1);
@@ -68,7 +123,82 @@ fn read_file(tmp: &str) -> String {
       println!("File not found: {:?}", e);
       std::process::exit(1);
     }
+  }
+}
+
+fn read_snapshot(path: &str) -> Snapshot {
+  let decoded = std::fs::read(path)
+    .map_err(|e| e.to_string())
+    .and_then(|data| Snapshot::decode(&data).map_err(|e| e.to_string()));
+  match decoded {
+    Ok(snapshot) => snapshot,
+    Err(e) => {
+      println!("Unable to read snapshot {}: {}", path, e);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Saves the VM state, as JSON when `path` ends in `.json`.
+fn write_snapshot(vm: &VM, path: &str) {
+  let snapshot = vm.snapshot();
+  let data = if path.ends_with(".json") {
+    snapshot.to_json().into_bytes()
+  } else {
+    snapshot.to_bytes()
   };
+  if let Err(e) = std::fs::write(path, data) {
+    println!("Unable to write snapshot {}: {}", path, e);
+  }
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+  let value = matches.value_of(name)?;
+  match value.parse() {
+    Ok(v) => Some(v),
+    Err(_) => {
+      println!("Invalid value for --{}: {}", name, value);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Installs a tracer when a trace file is given or trace logging is enabled.
+fn set_tracer(vm: &mut VM, matches: &ArgMatches) {
+  let filter = TraceFilter {
+    pc_range: matches.value_of("trace-pc").map(|value| {
+      match value.split_once("..").map(|(s, e)| (s.parse(), e.parse())) {
+        Some((Ok(start), Ok(end))) => start..end,
+        _ => {
+          println!("Invalid value for --trace-pc: {}", value);
+          std::process::exit(1);
+        }
+      }
+    }),
+    opcodes: matches.value_of("trace-opcode").map(|value| {
+      value
+        .split(',')
+        .map(|mnemonic| match Opcode::from_mnemonic(mnemonic.trim()) {
+          Some(opcode) => opcode,
+          None => {
+            println!("Invalid value for --trace-opcode: {}", mnemonic);
+            std::process::exit(1);
+          }
+        })
+        .collect()
+    }),
+  };
+  match matches.value_of("trace") {
+    Some(path) => match File::create(path) {
+      Ok(file) => vm.set_tracer(Tracer::with_sink(filter, Box::new(BufWriter::new(file)))),
+      Err(e) => {
+        println!("Unable to create trace file {}: {}", path, e);
+        std::process::exit(1);
+      }
+    },
+    None if log::log_enabled!(log::Level::Trace) => vm.set_tracer(Tracer::new(filter)),
+    None => {}
+  }
 }
 
 fn print_stars() {
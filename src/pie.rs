@@ -0,0 +1,263 @@
+//! PIE (Program Iridium Executable) image format.
+//!
+//! An image starts with a fixed 64-byte header followed by its sections, in
+//! this order and without gaps: read-only data, symbol table, code. The code
+//! section always runs to the end of the image. All multi-byte header fields
+//! are little-endian.
+//!
+//! | Offset | Size | Field                                           |
+//! |--------|------|-------------------------------------------------|
+//! | 0      | 4    | Magic bytes, `-21-` (`PIE_HEADER_PREFIX`)       |
+//! | 4      | 2    | Format version (`PIE_VERSION`)                  |
+//! | 6      | 2    | Flags, reserved and currently always zero       |
+//! | 8      | 4    | Read-only data offset                           |
+//! | 12     | 4    | Read-only data length                           |
+//! | 16     | 4    | Code offset                                     |
+//! | 20     | 4    | Code length                                     |
+//! | 24     | 4    | Entry point, absolute offset inside the code    |
+//! | 28     | 4    | Symbol table offset                             |
+//! | 32     | 4    | Symbol table length, zero when there is none    |
+//! | 36     | 28   | Reserved, zero                                  |
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+pub const PIE_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PieHeaderError {
+  TooShort { length: usize },
+  BadMagic,
+  UnsupportedVersion { version: u16 },
+  SectionMismatch { section: &'static str },
+  EntryPointOutOfBounds { entry_point: u32 },
+}
+
+impl fmt::Display for PieHeaderError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PieHeaderError::TooShort { length } => {
+        write!(f, "image is {} bytes, shorter than the header", length)
+      }
+      PieHeaderError::BadMagic => write!(f, "missing PIE magic bytes"),
+      PieHeaderError::UnsupportedVersion { version } => {
+        write!(f, "unsupported format version {}", version)
+      }
+      PieHeaderError::SectionMismatch { section } => {
+        write!(f, "{} section does not match the image layout", section)
+      }
+      PieHeaderError::EntryPointOutOfBounds { entry_point } => {
+        write!(f, "entry point {} is outside the code section", entry_point)
+      }
+    }
+  }
+}
+
+impl Error for PieHeaderError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PieHeader {
+  pub version: u16,
+  pub flags: u16,
+  pub ro_offset: u32,
+  pub ro_length: u32,
+  pub code_offset: u32,
+  pub code_length: u32,
+  pub entry_point: u32,
+  pub symtab_offset: u32,
+  pub symtab_length: u32,
+}
+
+impl PieHeader {
+  /// Lays out an image with the given section lengths and no symbol table,
+  /// entering at the start of the code.
+  pub fn new(ro_length: u32, code_length: u32) -> PieHeader {
+    let ro_offset = PIE_HEADER_LENGTH as u32;
+    let code_offset = ro_offset + ro_length;
+    PieHeader {
+      version: PIE_VERSION,
+      flags: 0,
+      ro_offset,
+      ro_length,
+      code_offset,
+      code_length,
+      entry_point: code_offset,
+      symtab_offset: code_offset,
+      symtab_length: 0,
+    }
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut header = vec![0; PIE_HEADER_LENGTH];
+    header[0..4].copy_from_slice(&PIE_HEADER_PREFIX);
+    LittleEndian::write_u16(&mut header[4..6], self.version);
+    LittleEndian::write_u16(&mut header[6..8], self.flags);
+    LittleEndian::write_u32(&mut header[8..12], self.ro_offset);
+    LittleEndian::write_u32(&mut header[12..16], self.ro_length);
+    LittleEndian::write_u32(&mut header[16..20], self.code_offset);
+    LittleEndian::write_u32(&mut header[20..24], self.code_length);
+    LittleEndian::write_u32(&mut header[24..28], self.entry_point);
+    LittleEndian::write_u32(&mut header[28..32], self.symtab_offset);
+    LittleEndian::write_u32(&mut header[32..36], self.symtab_length);
+    header
+  }
+
+  /// Reads the header at the start of `image` and checks that its sections
+  /// describe the rest of the image exactly.
+  pub fn from_bytes(image: &[u8]) -> Result<PieHeader, PieHeaderError> {
+    if image.len() < PIE_HEADER_LENGTH {
+      return Err(PieHeaderError::TooShort {
+        length: image.len(),
+      });
+    }
+    if image[0..4] != PIE_HEADER_PREFIX {
+      return Err(PieHeaderError::BadMagic);
+    }
+    let header = PieHeader {
+      version: LittleEndian::read_u16(&image[4..6]),
+      flags: LittleEndian::read_u16(&image[6..8]),
+      ro_offset: LittleEndian::read_u32(&image[8..12]),
+      ro_length: LittleEndian::read_u32(&image[12..16]),
+      code_offset: LittleEndian::read_u32(&image[16..20]),
+      code_length: LittleEndian::read_u32(&image[20..24]),
+      entry_point: LittleEndian::read_u32(&image[24..28]),
+      symtab_offset: LittleEndian::read_u32(&image[28..32]),
+      symtab_length: LittleEndian::read_u32(&image[32..36]),
+    };
+    header.validate(image.len())?;
+    Ok(header)
+  }
+
+  fn validate(&self, image_length: usize) -> Result<(), PieHeaderError> {
+    if self.version != PIE_VERSION {
+      return Err(PieHeaderError::UnsupportedVersion {
+        version: self.version,
+      });
+    }
+    let ro = self.ro_range();
+    let symtab = self.symtab_range();
+    let code = self.code_range();
+    if ro.start != PIE_HEADER_LENGTH {
+      return Err(PieHeaderError::SectionMismatch {
+        section: "read-only data",
+      });
+    }
+    if symtab.start != ro.end {
+      return Err(PieHeaderError::SectionMismatch {
+        section: "symbol table",
+      });
+    }
+    if code.start != symtab.end || code.end != image_length {
+      return Err(PieHeaderError::SectionMismatch { section: "code" });
+    }
+    if self.entry_point < self.code_offset || self.entry_point as usize > code.end {
+      return Err(PieHeaderError::EntryPointOutOfBounds {
+        entry_point: self.entry_point,
+      });
+    }
+    Ok(())
+  }
+
+  pub fn ro_range(&self) -> Range<usize> {
+    section_range(self.ro_offset, self.ro_length)
+  }
+
+  pub fn symtab_range(&self) -> Range<usize> {
+    section_range(self.symtab_offset, self.symtab_length)
+  }
+
+  pub fn code_range(&self) -> Range<usize> {
+    section_range(self.code_offset, self.code_length)
+  }
+}
+
+fn section_range(offset: u32, length: u32) -> Range<usize> {
+  offset as usize..offset as usize + length as usize
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_image(header: &PieHeader) -> Vec<u8> {
+    let mut image = header.to_bytes();
+    image.resize(header.code_range().end, 0);
+    image
+  }
+
+  #[test]
+  fn test_header_round_trip() {
+    let mut header = PieHeader::new(13, 8);
+    header.entry_point += 4;
+    let image = test_image(&header);
+    assert_eq!(image.len(), 64 + 13 + 8);
+    assert_eq!(&image[0..4], &PIE_HEADER_PREFIX);
+    assert_eq!(PieHeader::from_bytes(&image), Ok(header));
+  }
+
+  #[test]
+  fn test_header_layout() {
+    let bytes = PieHeader::new(3, 4).to_bytes();
+    assert_eq!(bytes.len(), PIE_HEADER_LENGTH);
+    assert_eq!(&bytes[4..6], &[1, 0]);
+    assert_eq!(&bytes[8..16], &[64, 0, 0, 0, 3, 0, 0, 0]);
+    assert_eq!(&bytes[16..28], &[67, 0, 0, 0, 4, 0, 0, 0, 67, 0, 0, 0]);
+    assert!(bytes[36..].iter().all(|b| *b == 0));
+  }
+
+  #[test]
+  fn test_reject_short_or_foreign_images() {
+    assert_eq!(
+      PieHeader::from_bytes(&[45, 50, 49, 45]),
+      Err(PieHeaderError::TooShort { length: 4 })
+    );
+    let mut image = test_image(&PieHeader::new(0, 4));
+    image[0] = 0;
+    assert_eq!(PieHeader::from_bytes(&image), Err(PieHeaderError::BadMagic));
+  }
+
+  #[test]
+  fn test_reject_unsupported_version() {
+    let mut header = PieHeader::new(0, 4);
+    header.version = 2;
+    assert_eq!(
+      PieHeader::from_bytes(&test_image(&header)),
+      Err(PieHeaderError::UnsupportedVersion { version: 2 })
+    );
+  }
+
+  #[test]
+  fn test_reject_mismatched_lengths() {
+    let mut image = test_image(&PieHeader::new(2, 4));
+    image.push(0);
+    assert_eq!(
+      PieHeader::from_bytes(&image),
+      Err(PieHeaderError::SectionMismatch { section: "code" })
+    );
+
+    let mut header = PieHeader::new(2, 4);
+    header.ro_length = 1;
+    assert_eq!(
+      PieHeader::from_bytes(&test_image(&header)),
+      Err(PieHeaderError::SectionMismatch {
+        section: "symbol table"
+      })
+    );
+  }
+
+  #[test]
+  fn test_reject_entry_point_outside_code() {
+    let mut header = PieHeader::new(2, 4);
+    header.entry_point = 10;
+    assert_eq!(
+      PieHeader::from_bytes(&test_image(&header)),
+      Err(PieHeaderError::EntryPointOutOfBounds { entry_point: 10 })
+    );
+  }
+}
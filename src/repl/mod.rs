@@ -1,25 +1,37 @@
-use super::assembler::program_parser::*;
-use super::assembler::*;
-use super::vm::VM;
-use nom::types::CompleteStr;
-use std;
-use std::fs::File;
+use iridium_vm::assembler::program_parser::*;
+use iridium_vm::assembler::*;
+use iridium_vm::disassembler::disassemble_instruction;
+use iridium_vm::instruction::Opcode;
+use iridium_vm::pie::PieHeader;
+use iridium_vm::vm::{ExitReason, RunLimits, Snapshot, StepResult, VM};
+use std::fs;
 use std::io;
-use std::io::prelude::*;
 use std::io::Write;
 use std::num::ParseIntError;
 use std::path::Path;
 
-pub struct REPL {
+/// Number of executed instructions that can be stepped back over.
+const HISTORY_LIMIT: usize = 10_000;
+
+pub struct Repl {
   command_buffer: Vec<String>,
   vm: VM,
   asm: Assembler,
 }
 
-impl REPL {
-  pub fn new() -> REPL {
-    REPL {
-      vm: VM::new(),
+impl Default for Repl {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Repl {
+  pub fn new() -> Repl {
+    let mut vm = VM::new();
+    vm.register_std_host_fns();
+    vm.set_history_limit(HISTORY_LIMIT);
+    Repl {
+      vm,
       command_buffer: vec![],
       asm: Assembler::new(),
     }
@@ -37,10 +49,15 @@ impl REPL {
       stdin
         .read_line(&mut buffer)
         .expect("Unable to read line from user");
-      let buffer = buffer.trim().to_lowercase();
+      let raw = buffer.trim().to_string();
+      let buffer = raw.to_lowercase();
       let input = String::from(&buffer);
       self.command_buffer.push(buffer);
-      match input.as_str() {
+      let mut words = input.split_whitespace();
+      let command = words.next().unwrap_or("");
+      // Arguments keep their case, they may be file paths
+      let argument = raw.split_whitespace().nth(1);
+      match command {
         ".quit" => {
           println!("Bye! Have a nice day!");
           std::process::exit(0);
@@ -52,21 +69,78 @@ impl REPL {
         }
         ".program" => {
           println!("Listing instructions in current VM's program vector:");
-          for instruction in self.vm.get_program() {
-            println!("{:?}", instruction);
+          let program = self.vm.get_program();
+          // Loaded files start with a header and their read-only data; bytes
+          // typed into the REPL are all code.
+          let code = match PieHeader::from_bytes(program) {
+            Ok(header) => header.code_range(),
+            Err(_) => 0..program.len(),
+          };
+          let mut offset = code.start;
+          while offset < code.end {
+            let width = Opcode::from(program[offset]).width();
+            match disassemble_instruction(program, offset) {
+              Ok(text) => println!("{:04}: {}", offset, text),
+              Err(e) => {
+                let end = code.end.min(offset + width);
+                println!("{:04}: {:?} ({})", offset, &program[offset..end], e);
+              }
+            }
+            offset += width;
           }
           println!("End of program listing");
         }
         ".registers" => {
           println!("Listing contents of VM registers");
           println!("{:?}", self.vm.get_registers());
+          println!("{:?}", self.vm.get_float_registers());
           println!("End of registers listing");
         }
+        ".heap" => {
+          println!("Listing contents of VM heap");
+          println!("{:?}", self.vm.get_heap());
+          println!("End of heap listing");
+        }
+        ".stack" => {
+          println!("Listing contents of VM stack");
+          println!("{:?}", self.vm.get_stack());
+          println!("End of stack listing");
+        }
+        ".flags" => {
+          println!("{:?}", self.vm.get_flags());
+        }
         ".dump" => {
           println!("---- Printing VM dump ----");
           print!("{:?}", self.vm);
           println!("---- End printing VM dump");
         }
+        ".snapshot" => match argument {
+          Some(path) => {
+            let snapshot = self.vm.snapshot();
+            let data = if path.ends_with(".json") {
+              snapshot.to_json().into_bytes()
+            } else {
+              snapshot.to_bytes()
+            };
+            match fs::write(path, data) {
+              Ok(()) => println!("VM state saved to {}", path),
+              Err(e) => println!("Unable to write {}: {}", path, e),
+            }
+          }
+          None => println!("Usage: .snapshot <file>"),
+        },
+        ".restore" => match argument {
+          Some(path) => match fs::read(path).map(|data| Snapshot::decode(&data)) {
+            Ok(Ok(snapshot)) => {
+              self.vm.restore(snapshot);
+              println!("VM state restored from {}", path);
+              self.print_current();
+            }
+            Ok(Err(e)) => println!("Unable to restore {}: {}", path, e),
+            Err(e) => println!("Unable to read {}: {}", path, e),
+          },
+          None => println!("Usage: .restore <file>"),
+        },
         ".symbols" => {
           println!("---- Printing symbols table ----");
           println!("{:?}", self.asm.symbols);
@@ -77,32 +151,103 @@ impl REPL {
           println!("Program vector cleared");
         }
         ".load_file" => {
-          print!("Enter file path: ");
-          io::stdout()
-            .flush()
-            .expect("Could not flush standard output");
-          let mut tmp = String::new();
-          stdin
-            .read_line(&mut tmp)
-            .expect("Unable to read line from user");
-          let tmp = tmp.trim();
-          let filename = Path::new(tmp);
-          let mut f = File::open(Path::new(&filename)).expect("File not found");
-          let mut contents = String::new();
-          f.read_to_string(&mut contents).expect("Error reading file");
-          let program = match program(CompleteStr(&contents)) {
-            Ok((_, program)) => program,
-            Err(e) => {
-              println!("Unable to parse input: {:?}", e);
-              continue;
+          let path = argument.map_or_else(Repl::ask_path, String::from);
+          if self.load_file(&path) {
+            match self.vm.run() {
+              Ok(reason) => println!("Program finished: {:?}", reason),
+              Err(e) => println!("Program crashed: {}", e),
             }
+          }
+        }
+        ".debug_file" => {
+          let path = argument.map_or_else(Repl::ask_path, String::from);
+          self.debug_file(&path);
+        }
+        ".break" => match argument.and_then(|a| self.resolve_address(a)) {
+          Some(pc) => {
+            self.vm.add_breakpoint(pc);
+            println!("Breakpoint set at {:04}", pc);
+          }
+          None => println!("Usage: .break <address or label>"),
+        },
+        ".delete" => match argument.and_then(|a| self.resolve_address(a)) {
+          Some(pc) if self.vm.remove_breakpoint(pc) => println!("Breakpoint at {:04} deleted", pc),
+          Some(pc) => println!("No breakpoint at {:04}", pc),
+          None => println!("Usage: .delete <address or label>"),
+        },
+        ".breakpoints" => {
+          for pc in self.vm.get_breakpoints() {
+            println!("{:04}", pc);
+          }
+        }
+        ".step" => {
+          let result = self.vm.step();
+          self.print_step(result);
+        }
+        ".next" => {
+          let result = self.vm.step_over();
+          self.print_step(result);
+        }
+        ".finish" => {
+          let result = self.vm.step_out();
+          self.print_step(result);
+        }
+        ".continue" => match self.vm.resume(RunLimits::default()) {
+          Ok(ExitReason::Breakpoint) => self.print_step(StepResult::Breakpoint {
+            pc: self.vm.get_pc(),
+          }),
+          Ok(reason) => println!("Program finished: {:?}", reason),
+          Err(e) => println!("Program crashed: {}", e),
+        },
+        ".back" => match argument.map_or(Ok(1), str::parse::<usize>) {
+          Ok(count) => {
+            let undone = (0..count).take_while(|_| self.vm.step_back()).count();
+            if undone < count {
+              println!("Stepped back {} instructions, no history left", undone);
+            }
+            self.print_current();
+          }
+          Err(_) => println!("Usage: .back [count]"),
+        },
+        ".reverse" => {
+          let register = argument.unwrap_or_default().trim_start_matches('$');
+          let (float, number) = match register.strip_prefix(['f', 'F']) {
+            Some(number) => (true, number),
+            None => (false, register),
           };
-          self.vm.program = program.to_bytes(&self.asm.symbols);
-          self.vm.run();
+          match number.parse::<usize>() {
+            Ok(number) => {
+              let found = if float {
+                self.vm.reverse_continue_float(number)
+              } else {
+                self.vm.reverse_continue(number)
+              };
+              if !found {
+                println!("No write to ${} left in the history", register);
+              }
+              self.print_current();
+            }
+            Err(_) => println!("Usage: .reverse <register>"),
+          }
         }
+        ".where" => self.print_current(),
         _ => {
-          let parsed_program = program(CompleteStr(&input));
-          if !parsed_program.is_ok() {
+          let (parsed_program, errors) = parse_program(&input);
+          if errors.is_empty() {
+            match parsed_program.to_bytes(&self.asm.symbols) {
+              Ok(bytecode) => {
+                for b in bytecode {
+                  self.vm.add_byte(b);
+                }
+              }
+              Err(errors) => {
+                for e in errors {
+                  println!("{}", e);
+                }
+                continue;
+              }
+            }
+          } else {
             let results = self.parse_hex(&input);
             match results {
               Ok(bytes) => {
@@ -114,26 +259,104 @@ impl REPL {
                 println!("Unable to parse input");
               }
             }
-          } else {
-            let (_, result) = parsed_program.unwrap();
-            let bytecode = result.to_bytes(&self.asm.symbols);
-            for b in bytecode {
-              self.vm.add_byte(b);
-            }
           }
 
-          self.vm.run_once();
+          match self.vm.run_once() {
+            Ok(Some(reason)) => println!("Program finished: {:?}", reason),
+            Ok(None) => {}
+            Err(e) => println!("Program crashed: {}", e),
+          }
         }
       }
     }
   }
 
+  /// Loads `path` and stops at its entry point, ready for stepping.
+  pub fn debug_file(&mut self, path: &str) {
+    if !self.load_file(path) {
+      return;
+    }
+    match self.vm.start() {
+      Ok(()) => self.print_current(),
+      Err(e) => println!("Unable to start program: {}", e),
+    }
+  }
+
+  fn ask_path() -> String {
+    print!("Enter file path: ");
+    io::stdout()
+      .flush()
+      .expect("Could not flush standard output");
+    let mut tmp = String::new();
+    io::stdin()
+      .read_line(&mut tmp)
+      .expect("Unable to read line from user");
+    tmp.trim().to_string()
+  }
+
+  /// Assembles `path` into the VM, printing any errors.
+  fn load_file(&mut self, path: &str) -> bool {
+    let contents = match fs::read_to_string(Path::new(path)) {
+      Ok(contents) => contents,
+      Err(e) => {
+        println!("Unable to read {}: {}", path, e);
+        return false;
+      }
+    };
+    self.asm = Assembler::new();
+    match self.asm.assemble(&contents) {
+      Ok(program) => {
+        self.vm.program = program;
+        true
+      }
+      Err(errors) => {
+        for e in errors {
+          println!("{}:{}", path, e);
+        }
+        false
+      }
+    }
+  }
+
+  /// Reads a breakpoint location, either a code address or a label.
+  fn resolve_address(&self, location: &str) -> Option<usize> {
+    location
+      .parse()
+      .ok()
+      .or_else(|| self.asm.symbols.symbol_value(location).map(|v| v as usize))
+  }
+
+  fn print_step(&self, result: StepResult) {
+    match result {
+      StepResult::Continue { .. } => self.print_current(),
+      StepResult::Breakpoint { pc } => {
+        println!("Breakpoint at {:04}", pc);
+        self.print_current();
+      }
+      StepResult::Exited(reason) => println!("Program finished: {:?}", reason),
+      StepResult::Trapped(e) => println!("Program crashed: {}", e),
+    }
+  }
+
+  /// Prints the instruction at the pc, the next one to run.
+  fn print_current(&self) {
+    let pc = self.vm.get_pc();
+    let label = match self.asm.symbols.symbol_at(pc as u32) {
+      Some(symbol) => format!("{}: ", symbol.name()),
+      None => String::new(),
+    };
+    match disassemble_instruction(self.vm.get_program(), pc) {
+      Ok(text) => println!("{:04}: {}{}", pc, label, text),
+      Err(e) => println!("{:04}: {}", pc, e),
+    }
+  }
+
   fn parse_hex(&mut self, i: &str) -> Result<Vec<u8>, ParseIntError> {
     let split = i.split(" ").collect::<Vec<&str>>();
     let mut results: Vec<u8> = vec![];
 
     for hex_string in split {
-      let byte = u8::from_str_radix(&hex_string, 16);
+      let byte = u8::from_str_radix(hex_string, 16);
       match byte {
         Ok(result) => {
           results.push(result);
@@ -0,0 +1,146 @@
+use crate::pie::PieHeaderError;
+use std::error::Error;
+use std::fmt;
+
+/// Reason why a program stopped running without crashing.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitReason {
+  /// A `HLT` instruction was executed.
+  Halted,
+  /// The program counter reached the end of the program.
+  EndOfProgram,
+  /// The run executed its maximum number of instructions.
+  FuelExhausted,
+  /// The run went past its deadline.
+  Timeout,
+  /// An allocation would have grown the heap past its limit.
+  MemoryLimit,
+  /// Execution reached a breakpoint, the instruction there has not run yet.
+  Breakpoint,
+}
+
+/// Outcome of executing a single instruction with `VM::step`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StepResult {
+  /// The instruction ran, execution continues at `pc`.
+  Continue { pc: usize },
+  /// The instruction ran and the next one, at `pc`, has a breakpoint.
+  Breakpoint { pc: usize },
+  /// The program stopped.
+  Exited(ExitReason),
+  /// The instruction failed.
+  Trapped(VmError),
+}
+
+/// Fatal condition raised while executing a program. Every variant carrying a
+/// `pc` reports the address of the instruction that failed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+  InvalidHeader(PieHeaderError),
+  IllegalOpcode {
+    pc: usize,
+    byte: u8,
+  },
+  RegisterOutOfRange {
+    pc: usize,
+    register: u8,
+  },
+  DivideByZero {
+    pc: usize,
+  },
+  /// Only raised when the VM is set to trap on overflow.
+  ArithmeticOverflow {
+    pc: usize,
+  },
+  PcOutOfBounds {
+    pc: usize,
+    target: i64,
+  },
+  TruncatedInstruction {
+    pc: usize,
+  },
+  HeapExhausted {
+    pc: usize,
+    requested: i32,
+  },
+  InvalidStringOffset {
+    pc: usize,
+    offset: usize,
+  },
+  OutputError {
+    pc: usize,
+    message: String,
+  },
+  StackOverflow {
+    pc: usize,
+  },
+  StackUnderflow {
+    pc: usize,
+  },
+  HeapOutOfBounds {
+    pc: usize,
+    address: i64,
+  },
+  InvalidFree {
+    pc: usize,
+    requested: i32,
+  },
+  UnknownSyscall {
+    pc: usize,
+    id: u16,
+  },
+  HostError {
+    pc: usize,
+    syscall: u16,
+    message: String,
+  },
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      VmError::InvalidHeader(e) => write!(f, "invalid program header: {}", e),
+      VmError::IllegalOpcode { pc, byte } => {
+        write!(f, "illegal opcode {} at pc {}", byte, pc)
+      }
+      VmError::RegisterOutOfRange { pc, register } => {
+        write!(f, "register ${} out of range at pc {}", register, pc)
+      }
+      VmError::DivideByZero { pc } => write!(f, "division by zero at pc {}", pc),
+      VmError::ArithmeticOverflow { pc } => write!(f, "arithmetic overflow at pc {}", pc),
+      VmError::PcOutOfBounds { pc, target } => {
+        write!(f, "jump to {} out of program bounds at pc {}", target, pc)
+      }
+      VmError::TruncatedInstruction { pc } => {
+        write!(f, "truncated instruction at pc {}", pc)
+      }
+      VmError::HeapExhausted { pc, requested } => {
+        write!(f, "unable to allocate {} bytes at pc {}", requested, pc)
+      }
+      VmError::InvalidStringOffset { pc, offset } => write!(
+        f,
+        "no NUL-terminated string at read-only offset {} at pc {}",
+        offset, pc
+      ),
+      VmError::OutputError { pc, message } => {
+        write!(f, "unable to write output at pc {}: {}", pc, message)
+      }
+      VmError::StackOverflow { pc } => write!(f, "stack overflow at pc {}", pc),
+      VmError::StackUnderflow { pc } => write!(f, "stack underflow at pc {}", pc),
+      VmError::HeapOutOfBounds { pc, address } => {
+        write!(f, "heap access at {} out of bounds at pc {}", address, pc)
+      }
+      VmError::InvalidFree { pc, requested } => {
+        write!(f, "unable to free {} heap bytes at pc {}", requested, pc)
+      }
+      VmError::UnknownSyscall { pc, id } => write!(f, "unknown syscall {} at pc {}", id, pc),
+      VmError::HostError {
+        pc,
+        syscall,
+        message,
+      } => write!(f, "syscall {} failed at pc {}: {}", syscall, pc, message),
+    }
+  }
+}
+
+impl Error for VmError {}
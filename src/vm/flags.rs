@@ -0,0 +1,190 @@
+use crate::instruction::Opcode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Condition flags, set by arithmetic and `CMP` and tested by the
+/// conditional jumps. `carry` means an unsigned carry for additions and a
+/// borrow for subtractions. `unordered` is only set by `CMPF` on a NaN.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Flags {
+  pub zero: bool,
+  pub negative: bool,
+  pub carry: bool,
+  pub overflow: bool,
+  pub unordered: bool,
+}
+
+impl Flags {
+  /// Flags of an operation that neither carries nor overflows.
+  pub fn from_result(result: i32) -> Flags {
+    Flags {
+      zero: result == 0,
+      negative: result < 0,
+      carry: false,
+      overflow: false,
+      unordered: false,
+    }
+  }
+
+  /// Wrapping `a + b` and its flags.
+  pub fn add(a: i32, b: i32) -> (i32, Flags) {
+    let (result, overflow) = a.overflowing_add(b);
+    let (_, carry) = (a as u32).overflowing_add(b as u32);
+    (result, Flags::from_result(result).with(carry, overflow))
+  }
+
+  /// Wrapping `a - b` and its flags.
+  pub fn sub(a: i32, b: i32) -> (i32, Flags) {
+    let (result, overflow) = a.overflowing_sub(b);
+    let (_, carry) = (a as u32).overflowing_sub(b as u32);
+    (result, Flags::from_result(result).with(carry, overflow))
+  }
+
+  /// Wrapping `a * b` and its flags.
+  pub fn mul(a: i32, b: i32) -> (i32, Flags) {
+    let (result, overflow) = a.overflowing_mul(b);
+    let (_, carry) = (a as u32).overflowing_mul(b as u32);
+    (result, Flags::from_result(result).with(carry, overflow))
+  }
+
+  /// Flags of the float comparison `CMPF a b`: `a < b` sets negative and
+  /// carry, so both the signed and unsigned jumps work. Unordered operands
+  /// (a NaN) set unordered and overflow, so only `JNE` and `JO` are taken.
+  pub fn compare_floats(a: f64, b: f64) -> Flags {
+    let less = a < b;
+    let unordered = a.partial_cmp(&b).is_none();
+    Flags {
+      zero: a == b,
+      negative: less,
+      carry: less,
+      overflow: unordered,
+      unordered,
+    }
+  }
+
+  fn with(mut self, carry: bool, overflow: bool) -> Flags {
+    self.carry = carry;
+    self.overflow = overflow;
+    self
+  }
+
+  /// Whether the conditional jump `opcode` is taken. The signed and unsigned
+  /// orderings read the flags left by `CMP a b`, as in "a > b".
+  pub fn allows(&self, opcode: Opcode) -> bool {
+    let less = self.negative != self.overflow;
+    match opcode {
+      Opcode::JNE | Opcode::JO if self.unordered => true,
+      _ if self.unordered => false,
+      Opcode::JEQ => self.zero,
+      Opcode::JNE => !self.zero,
+      Opcode::JGT => !self.zero && !less,
+      Opcode::JLT => less,
+      Opcode::JGE => !less,
+      Opcode::JLE => self.zero || less,
+      Opcode::JGTU => !self.zero && !self.carry,
+      Opcode::JLTU => self.carry,
+      Opcode::JGEU => !self.carry,
+      Opcode::JLEU => self.zero || self.carry,
+      Opcode::JC => self.carry,
+      Opcode::JO => self.overflow,
+      _ => false,
+    }
+  }
+
+  /// Packs the flags as bits 0 to 4: zero, negative, carry, overflow,
+  /// unordered.
+  pub fn bits(&self) -> u8 {
+    self.zero as u8
+      | (self.negative as u8) << 1
+      | (self.carry as u8) << 2
+      | (self.overflow as u8) << 3
+      | (self.unordered as u8) << 4
+  }
+
+  pub fn from_bits(bits: u8) -> Flags {
+    Flags {
+      zero: bits & 1 != 0,
+      negative: bits & 2 != 0,
+      carry: bits & 4 != 0,
+      overflow: bits & 8 != 0,
+      unordered: bits & 16 != 0,
+    }
+  }
+}
+
+impl fmt::Display for Flags {
+  /// Set flags as letters, clear ones as dashes, like `Z-C--`.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (set, letter) in [
+      (self.zero, 'Z'),
+      (self.negative, 'N'),
+      (self.carry, 'C'),
+      (self.overflow, 'V'),
+      (self.unordered, 'U'),
+    ] {
+      write!(f, "{}", if set { letter } else { '-' })?;
+    }
+    Ok(())
+  }
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_arithmetic_flags() {
+    assert_eq!(Flags::add(2, -2), (0, Flags::from_bits(0b0101)));
+    assert_eq!(
+      Flags::add(i32::MAX, 1),
+      (i32::MIN, Flags::from_bits(0b1010))
+    );
+    assert_eq!(Flags::sub(1, 2), (-1, Flags::from_bits(0b0110)));
+    assert_eq!(
+      Flags::sub(i32::MIN, 1),
+      (i32::MAX, Flags::from_bits(0b1000))
+    );
+    assert_eq!(Flags::mul(-1, 3), (-3, Flags::from_bits(0b0110)));
+    assert_eq!(Flags::from_bits(0b11111).bits(), 0b11111);
+  }
+
+  #[test]
+  fn test_conditions_after_compare() {
+    let compare = |a, b| Flags::sub(a, b).1;
+    let taken = |a, b, opcode| compare(a, b).allows(opcode);
+    assert!(taken(3, 3, Opcode::JEQ) && !taken(3, 4, Opcode::JEQ));
+    assert!(taken(3, 4, Opcode::JNE));
+    assert!(taken(4, -3, Opcode::JGT) && !taken(-3, 4, Opcode::JGT));
+    assert!(taken(i32::MIN, 1, Opcode::JLT));
+    assert!(taken(3, 3, Opcode::JGE) && taken(3, 3, Opcode::JLE));
+    // -3 is a large unsigned number
+    assert!(taken(-3, 4, Opcode::JGTU) && taken(4, -3, Opcode::JLTU));
+    assert!(taken(4, 4, Opcode::JGEU) && taken(4, 4, Opcode::JLEU));
+    assert!(!taken(4, 5, Opcode::JGEU));
+    assert!(taken(1, 2, Opcode::JC) && taken(i32::MIN, 1, Opcode::JO));
+    assert_eq!(compare(1, 2).to_string(), "-NC--");
+  }
+
+  #[test]
+  fn test_conditions_after_unordered_compare() {
+    let flags = Flags::compare_floats(f64::NAN, 1.0);
+    assert_eq!(flags.to_string(), "---VU");
+    for opcode in [
+      Opcode::JEQ,
+      Opcode::JGT,
+      Opcode::JLT,
+      Opcode::JGE,
+      Opcode::JLE,
+      Opcode::JGTU,
+      Opcode::JLTU,
+      Opcode::JGEU,
+      Opcode::JLEU,
+      Opcode::JC,
+    ] {
+      assert!(!flags.allows(opcode), "{:?}", opcode);
+    }
+    assert!(flags.allows(Opcode::JNE) && flags.allows(Opcode::JO));
+  }
+}
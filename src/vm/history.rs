@@ -0,0 +1,86 @@
+//! Undo log for reverse execution. Every executed instruction records the
+//! state it overwrote, so the VM can step backwards. Program output and host
+//! function side effects other than registers and heap cannot be undone.
+
+use super::{Flags, VM};
+use std::ops::Range;
+
+/// Heap bytes overwritten by an instruction, `old` starting at `start`,
+/// when the heap was `len` bytes long.
+#[derive(Debug, Clone)]
+pub(super) struct HeapUndo {
+  len: usize,
+  start: usize,
+  old: Vec<u8>,
+}
+
+impl HeapUndo {
+  pub(super) fn new(heap: &[u8], range: Range<usize>) -> HeapUndo {
+    HeapUndo {
+      len: heap.len(),
+      start: range.start,
+      old: heap[range].to_vec(),
+    }
+  }
+}
+
+/// State overwritten by one executed instruction.
+#[derive(Debug, Clone)]
+pub(super) struct UndoEntry {
+  pub(super) pc: usize,
+  pub(super) registers: Vec<(usize, i32)>,
+  pub(super) float_registers: Vec<(usize, f64)>,
+  pub(super) flags: Flags,
+  pub(super) reminder: u32,
+  pub(super) stack_len: usize,
+  pub(super) stack_top: Option<i32>,
+  pub(super) heap: Option<HeapUndo>,
+}
+
+impl UndoEntry {
+  /// Captures the state before running the instruction at the pc. Changed
+  /// registers and heap bytes are filled in once it ran.
+  pub(super) fn before(vm: &VM) -> UndoEntry {
+    UndoEntry {
+      pc: vm.pc,
+      registers: vec![],
+      float_registers: vec![],
+      flags: vm.flags,
+      reminder: vm.reminder,
+      stack_len: vm.stack.len(),
+      stack_top: vm.stack.last().copied(),
+      heap: None,
+    }
+  }
+
+  pub(super) fn wrote_register(&self, register: usize) -> bool {
+    self.registers.iter().any(|(r, _)| *r == register)
+  }
+
+  pub(super) fn wrote_float_register(&self, register: usize) -> bool {
+    self.float_registers.iter().any(|(r, _)| *r == register)
+  }
+
+  /// Puts the VM back in the state it was before the instruction ran. An
+  /// instruction pushes or pops at most one stack value.
+  pub(super) fn undo(self, vm: &mut VM) {
+    vm.pc = self.pc;
+    vm.instruction_pc = self.pc;
+    for (register, value) in self.registers {
+      vm.registers[register] = value;
+    }
+    for (register, value) in self.float_registers {
+      vm.float_registers[register] = value;
+    }
+    vm.flags = self.flags;
+    vm.reminder = self.reminder;
+    vm.stack.truncate(self.stack_len);
+    if vm.stack.len() < self.stack_len {
+      vm.stack.extend(self.stack_top);
+    }
+    if let Some(heap) = self.heap {
+      vm.heap.resize(heap.len, 0);
+      vm.heap[heap.start..heap.start + heap.old.len()].copy_from_slice(&heap.old);
+    }
+  }
+}
@@ -0,0 +1,127 @@
+//! Host functions, called by programs with `SYSCALL #id`.
+//!
+//! A host function receives a `VmContext`, reads its arguments from the
+//! registers and writes its results back to them. The standard functions
+//! installed by `VM::register_std_host_fns` are:
+//!
+//! | Id | Name        | Arguments                          | Results                                   |
+//! |----|-------------|------------------------------------|-------------------------------------------|
+//! | 0  | print int   | `$0` value                         |                                           |
+//! | 1  | read line   | `$0` heap address, `$1` max length | `$0` bytes read, -1 at end of input       |
+//! | 2  | time        |                                    | `$0` seconds since the Unix epoch, `$1` ms |
+//! | 3  | random      | `$0` bound                         | `$0` in `0..bound`, any value if bound <= 0 |
+
+use super::error::VmError;
+use crate::instruction::REGISTER_COUNT;
+use std::io::{BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SYSCALL_PRINT_INT: u16 = 0;
+pub const SYSCALL_READ_LINE: u16 = 1;
+pub const SYSCALL_TIME: u16 = 2;
+pub const SYSCALL_RANDOM: u16 = 3;
+
+pub type HostFn = Box<dyn FnMut(&mut VmContext) -> Result<(), VmError>>;
+
+/// Part of the VM state a host function can work with.
+pub struct VmContext<'a> {
+  pub registers: &'a mut [i32; REGISTER_COUNT],
+  pub heap: &'a mut [u8],
+  pub ro_data: &'a [u8],
+  pub(super) pc: usize,
+  pub(super) syscall: u16,
+  pub(super) output: &'a mut dyn Write,
+  pub(super) input: &'a mut dyn BufRead,
+}
+
+impl<'a> VmContext<'a> {
+  /// Address of the `SYSCALL` instruction being served.
+  pub fn pc(&self) -> usize {
+    self.pc
+  }
+
+  pub fn output(&mut self) -> &mut dyn Write {
+    self.output
+  }
+
+  pub fn input(&mut self) -> &mut dyn BufRead {
+    self.input
+  }
+
+  /// Builds the error a host function returns to make the program crash.
+  pub fn error(&self, message: &str) -> VmError {
+    VmError::HostError {
+      pc: self.pc,
+      syscall: self.syscall,
+      message: message.to_string(),
+    }
+  }
+}
+
+/// Writes `$0` in decimal to the program output.
+pub fn print_int(context: &mut VmContext) -> Result<(), VmError> {
+  let value = context.registers[0];
+  let pc = context.pc;
+  writeln!(context.output(), "{}", value)
+    .and_then(|_| context.output().flush())
+    .map_err(|e| VmError::OutputError {
+      pc,
+      message: e.to_string(),
+    })
+}
+
+/// Reads one line from the input into the heap at `$0`, keeping at most `$1`
+/// bytes and dropping the line terminator.
+pub fn read_line(context: &mut VmContext) -> Result<(), VmError> {
+  let address = context.registers[0] as i64;
+  let max_length = context.registers[1].max(0) as usize;
+  let mut line = String::new();
+  let read = context
+    .input()
+    .read_line(&mut line)
+    .map_err(|e| context.error(&e.to_string()))?;
+  if read == 0 {
+    context.registers[0] = -1;
+    return Ok(());
+  }
+  let bytes = line.trim_end_matches(['\n', '\r']).as_bytes();
+  let bytes = &bytes[..bytes.len().min(max_length)];
+  if address < 0 || address as usize + bytes.len() > context.heap.len() {
+    return Err(VmError::HeapOutOfBounds {
+      pc: context.pc,
+      address,
+    });
+  }
+  let start = address as usize;
+  context.heap[start..start + bytes.len()].copy_from_slice(bytes);
+  context.registers[0] = bytes.len() as i32;
+  Ok(())
+}
+
+/// Stores the current Unix time, the seconds truncated to 32 bits.
+pub fn time(context: &mut VmContext) -> Result<(), VmError> {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_err(|e| context.error(&e.to_string()))?;
+  context.registers[0] = now.as_secs() as i32;
+  context.registers[1] = now.subsec_millis() as i32;
+  Ok(())
+}
+
+/// Builds a random number host function. The generator is a xorshift, fine
+/// for programs but not for anything security related.
+pub fn random(seed: u64) -> HostFn {
+  let mut state = seed | 1;
+  Box::new(move |context| {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    let bound = context.registers[0];
+    context.registers[0] = if bound > 0 {
+      (state % bound as u64) as i32
+    } else {
+      state as i32
+    };
+    Ok(())
+  })
+}
@@ -0,0 +1,2045 @@
+use super::disassembler::disassemble_instruction;
+use super::instruction::{Opcode, REGISTER_COUNT};
+use super::pie::PieHeader;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub mod error;
+pub mod flags;
+mod history;
+pub mod host;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
+
+pub use self::error::{ExitReason, StepResult, VmError};
+pub use self::flags::Flags;
+pub use self::host::{HostFn, VmContext};
+pub use self::profile::Profile;
+pub use self::snapshot::{Snapshot, SnapshotError};
+pub use self::trace::{TraceEvent, TraceFilter, Tracer};
+
+/// Maximum number of values on the stack unless configured otherwise.
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+/// Number of instructions executed between two checks of the run deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Resource limits for a single run. `None` leaves a resource unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct RunLimits {
+  pub max_instructions: Option<u64>,
+  pub max_heap_bytes: Option<usize>,
+  pub deadline: Option<Instant>,
+}
+
+/// Destination of everything printed by the running program.
+struct Output(Box<dyn Write>);
+
+impl fmt::Debug for Output {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Output")
+  }
+}
+
+/// Source of everything read by the running program.
+struct Input(Box<dyn BufRead>);
+
+impl fmt::Debug for Input {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Input")
+  }
+}
+
+/// Host functions by syscall id.
+#[derive(Default)]
+struct HostFns(HashMap<u16, HostFn>);
+
+impl fmt::Debug for HostFns {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut ids: Vec<&u16> = self.0.keys().collect();
+    ids.sort();
+    write!(f, "HostFns{:?}", ids)
+  }
+}
+
+#[derive(Debug)]
+pub struct VM {
+  pub registers: [i32; REGISTER_COUNT],
+  pub float_registers: [f64; REGISTER_COUNT],
+  pc: usize,
+  pub program: Vec<u8>,
+  heap: Vec<u8>,
+  reminder: u32,
+  flags: Flags,
+  ro_data: Vec<u8>,
+  stack: Vec<i32>,
+  stack_limit: usize,
+  heap_limit: Option<usize>,
+  instruction_pc: usize,
+  output: Output,
+  input: Input,
+  host_fns: HostFns,
+  breakpoints: BTreeSet<usize>,
+  tracer: Option<Tracer>,
+  profile: Option<Profile>,
+  history: VecDeque<history::UndoEntry>,
+  history_limit: usize,
+  heap_undo: Option<history::HeapUndo>,
+  trap_overflow: bool,
+}
+
+impl Default for VM {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl VM {
+  pub fn new() -> VM {
+    VM {
+      registers: [0; REGISTER_COUNT],
+      float_registers: [0.0; REGISTER_COUNT],
+      program: vec![],
+      pc: 0,
+      heap: vec![],
+      reminder: 0,
+      flags: Flags::default(),
+      ro_data: vec![],
+      stack: vec![],
+      stack_limit: DEFAULT_STACK_LIMIT,
+      heap_limit: None,
+      instruction_pc: 0,
+      output: Output(Box::new(io::stdout())),
+      input: Input(Box::new(BufReader::new(io::stdin()))),
+      host_fns: HostFns::default(),
+      breakpoints: BTreeSet::new(),
+      tracer: None,
+      profile: None,
+      history: VecDeque::new(),
+      history_limit: 0,
+      heap_undo: None,
+      trap_overflow: false,
+    }
+  }
+
+  pub fn get_program(&self) -> &Vec<u8> {
+    &self.program
+  }
+
+  pub fn get_registers(&self) -> &[i32] {
+    &self.registers
+  }
+
+  pub fn get_float_registers(&self) -> &[f64] {
+    &self.float_registers
+  }
+
+  pub fn get_pc(&self) -> usize {
+    self.pc
+  }
+
+  pub fn get_ro_data(&self) -> &[u8] {
+    &self.ro_data
+  }
+
+  pub fn get_heap(&self) -> &[u8] {
+    &self.heap
+  }
+
+  pub fn get_stack(&self) -> &[i32] {
+    &self.stack
+  }
+
+  pub fn get_flags(&self) -> Flags {
+    self.flags
+  }
+
+  pub fn get_breakpoints(&self) -> &BTreeSet<usize> {
+    &self.breakpoints
+  }
+
+  /// Makes execution loops stop before running the instruction at `pc`.
+  /// Returns false if there already was a breakpoint there.
+  pub fn add_breakpoint(&mut self, pc: usize) -> bool {
+    self.breakpoints.insert(pc)
+  }
+
+  pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+    self.breakpoints.remove(&pc)
+  }
+
+  /// Sets the maximum number of values the stack can hold, shared by return
+  /// addresses and pushed registers.
+  pub fn set_stack_limit(&mut self, limit: usize) {
+    self.stack_limit = limit;
+  }
+
+  /// Redirects program output, which goes to stdout by default.
+  pub fn set_output(&mut self, output: Box<dyn Write>) {
+    self.output = Output(output);
+  }
+
+  /// Redirects program input, which comes from stdin by default.
+  pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+    self.input = Input(input);
+  }
+
+  /// Makes `host_fn` callable with `SYSCALL #id`, replacing any function
+  /// already registered under `id`.
+  pub fn register_host_fn(&mut self, id: u16, host_fn: HostFn) {
+    self.host_fns.0.insert(id, host_fn);
+  }
+
+  /// Registers the standard host functions described in `host`.
+  pub fn register_std_host_fns(&mut self) {
+    let seed = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |d| d.as_nanos() as u64);
+    self.register_host_fn(host::SYSCALL_PRINT_INT, Box::new(host::print_int));
+    self.register_host_fn(host::SYSCALL_READ_LINE, Box::new(host::read_line));
+    self.register_host_fn(host::SYSCALL_TIME, Box::new(host::time));
+    self.register_host_fn(host::SYSCALL_RANDOM, host::random(seed));
+  }
+
+  /// Records every executed instruction selected by the tracer's filter.
+  pub fn set_tracer(&mut self, tracer: Tracer) {
+    self.tracer = Some(tracer);
+  }
+
+  /// Removes the tracer, giving it back so its output can be flushed.
+  pub fn take_tracer(&mut self) -> Option<Tracer> {
+    self.tracer.take()
+  }
+
+  /// Starts collecting a `Profile` of the following runs.
+  pub fn enable_profiling(&mut self) {
+    self.profile = Some(Profile::default());
+  }
+
+  pub fn get_profile(&self) -> Option<&Profile> {
+    self.profile.as_ref()
+  }
+
+  /// Stops profiling, returning what was collected.
+  pub fn take_profile(&mut self) -> Option<Profile> {
+    self.profile.take()
+  }
+
+  /// Makes arithmetic instructions whose signed result does not fit in 32
+  /// bits fail with `VmError::ArithmeticOverflow`, instead of wrapping around
+  /// and setting the overflow flag.
+  pub fn set_trap_on_overflow(&mut self, trap: bool) {
+    self.trap_overflow = trap;
+  }
+
+  /// Keeps the undo log of the last `limit` executed instructions, needed to
+  /// step backwards. Zero, the default, disables it.
+  pub fn set_history_limit(&mut self, limit: usize) {
+    self.history_limit = limit;
+    while self.history.len() > limit {
+      self.history.pop_front();
+    }
+  }
+
+  /// Number of instructions that can currently be undone.
+  pub fn history_len(&self) -> usize {
+    self.history.len()
+  }
+
+  /// Undoes the last executed instruction. Returns false when there is no
+  /// history left.
+  pub fn step_back(&mut self) -> bool {
+    match self.history.pop_back() {
+      Some(entry) => {
+        entry.undo(self);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Steps backwards until undoing the last instruction that wrote
+  /// `register`, leaving the pc on it. Returns false if no such write is left
+  /// in the history, which is then exhausted.
+  pub fn reverse_continue(&mut self, register: usize) -> bool {
+    self.reverse_until(|entry| entry.wrote_register(register))
+  }
+
+  /// Like `reverse_continue`, for the float register `register`.
+  pub fn reverse_continue_float(&mut self, register: usize) -> bool {
+    self.reverse_until(|entry| entry.wrote_float_register(register))
+  }
+
+  fn reverse_until(&mut self, found: impl Fn(&history::UndoEntry) -> bool) -> bool {
+    while let Some(entry) = self.history.pop_back() {
+      let found = found(&entry);
+      entry.undo(self);
+      if found {
+        return true;
+      }
+    }
+    false
+  }
+
+  /// Captures the execution state, to be resumed later with `restore`.
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot {
+      version: snapshot::SNAPSHOT_VERSION,
+      registers: self.registers,
+      float_registers: self.float_registers,
+      pc: self.pc,
+      reminder: self.reminder,
+      flags: self.flags,
+      program: self.program.clone(),
+      heap: self.heap.clone(),
+      ro_data: self.ro_data.clone(),
+      stack: self.stack.clone(),
+    }
+  }
+
+  /// Replaces the execution state with `snapshot`. Continue the run with
+  /// `resume`, since `run` would start over from the entry point.
+  pub fn restore(&mut self, snapshot: Snapshot) {
+    self.registers = snapshot.registers;
+    self.float_registers = snapshot.float_registers;
+    self.pc = snapshot.pc;
+    self.instruction_pc = snapshot.pc;
+    self.reminder = snapshot.reminder;
+    self.flags = snapshot.flags;
+    self.program = snapshot.program;
+    self.heap = snapshot.heap;
+    self.ro_data = snapshot.ro_data;
+    self.stack = snapshot.stack;
+    self.history.clear();
+  }
+
+  pub fn add_byte(&mut self, byte: u8) {
+    self.program.push(byte);
+  }
+
+  /// Verifies the program header and executes instructions until the program
+  /// halts or fails.
+  pub fn run(&mut self) -> Result<ExitReason, VmError> {
+    self.run_with_limits(RunLimits::default())
+  }
+
+  /// Like `run`, but stops early once any of the `limits` is reached.
+  pub fn run_with_limits(&mut self, limits: RunLimits) -> Result<ExitReason, VmError> {
+    self.start()?;
+    self.resume(limits)
+  }
+
+  /// Checks the program header and moves the pc to the entry point without
+  /// executing anything, ready for `step` or `resume`.
+  pub fn start(&mut self) -> Result<(), VmError> {
+    self.verify_header()
+  }
+
+  /// Executes instructions from the current pc until the program stops, a
+  /// limit is reached or a breakpoint other than the one at the current pc
+  /// is hit.
+  pub fn resume(&mut self, limits: RunLimits) -> Result<ExitReason, VmError> {
+    self.heap_limit = limits.max_heap_bytes;
+    let mut executed: u64 = 0;
+    loop {
+      if executed > 0 && self.breakpoints.contains(&self.pc) {
+        return Ok(ExitReason::Breakpoint);
+      }
+      if let Some(max_instructions) = limits.max_instructions {
+        if executed >= max_instructions {
+          return Ok(ExitReason::FuelExhausted);
+        }
+      }
+      if let Some(deadline) = limits.deadline {
+        if executed.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+          return Ok(ExitReason::Timeout);
+        }
+      }
+      if let Some(reason) = self.execute_instruction()? {
+        return Ok(reason);
+      }
+      executed += 1;
+    }
+  }
+
+  /// Executes a single instruction. Returns `Some` once the program has
+  /// finished running.
+  pub fn run_once(&mut self) -> Result<Option<ExitReason>, VmError> {
+    self.execute_instruction()
+  }
+
+  /// Executes a single instruction, reporting whether it landed on a
+  /// breakpoint.
+  pub fn step(&mut self) -> StepResult {
+    match self.execute_instruction() {
+      Ok(Some(reason)) => StepResult::Exited(reason),
+      Ok(None) if self.breakpoints.contains(&self.pc) => StepResult::Breakpoint { pc: self.pc },
+      Ok(None) => StepResult::Continue { pc: self.pc },
+      Err(e) => StepResult::Trapped(e),
+    }
+  }
+
+  /// Like `step`, but runs a whole subroutine when the instruction is a
+  /// `CALL`, stopping early at breakpoints.
+  pub fn step_over(&mut self) -> StepResult {
+    let depth = self.stack.len();
+    let is_call = self.current_opcode() == Some(Opcode::CALL);
+    let mut result = self.step();
+    while is_call && self.stack.len() > depth {
+      match result {
+        StepResult::Continue { .. } => result = self.step(),
+        _ => break,
+      }
+    }
+    result
+  }
+
+  /// Runs until the current subroutine returns, stopping early at
+  /// breakpoints. Values pushed by the subroutine must have been popped by
+  /// the time it returns.
+  pub fn step_out(&mut self) -> StepResult {
+    let depth = self.stack.len();
+    loop {
+      let returning = self.current_opcode() == Some(Opcode::RET) && self.stack.len() <= depth;
+      let result = self.step();
+      match result {
+        StepResult::Continue { .. } if !returning => {}
+        _ => return result,
+      }
+    }
+  }
+
+  fn current_opcode(&self) -> Option<Opcode> {
+    self.program.get(self.pc).map(|byte| Opcode::from(*byte))
+  }
+
+  pub fn clear(&mut self) {
+    self.program = vec![];
+    self.pc = 0;
+    self.stack.clear();
+    self.history.clear();
+    self.heap_undo = None;
+  }
+
+  /// Checks the header, loads the read-only data section and moves the pc to
+  /// the entry point.
+  fn verify_header(&mut self) -> Result<(), VmError> {
+    let header = PieHeader::from_bytes(&self.program).map_err(VmError::InvalidHeader)?;
+    self.ro_data = self.program[header.ro_range()].to_vec();
+    self.pc = header.entry_point as usize;
+    self.history.clear();
+    if let Some(profile) = &mut self.profile {
+      profile.start(header.code_offset as usize, self.pc);
+    }
+    Ok(())
+  }
+
+  /// Returns the NUL-terminated string starting at `offset` in the read-only
+  /// data, without the terminator.
+  fn ro_string(&self, offset: usize) -> Result<&[u8], VmError> {
+    let bytes = self.ro_data.get(offset..).unwrap_or(&[]);
+    match bytes.iter().position(|b| *b == 0) {
+      Some(end) => Ok(&bytes[..end]),
+      None => Err(VmError::InvalidStringOffset {
+        pc: self.instruction_pc,
+        offset,
+      }),
+    }
+  }
+
+  fn next_8_bits(&mut self) -> Result<u8, VmError> {
+    match self.program.get(self.pc) {
+      Some(byte) => {
+        self.pc += 1;
+        Ok(*byte)
+      }
+      None => Err(VmError::TruncatedInstruction {
+        pc: self.instruction_pc,
+      }),
+    }
+  }
+
+  fn next_16_bits(&mut self) -> Result<u16, VmError> {
+    let high = self.next_8_bits()? as u16;
+    let low = self.next_8_bits()? as u16;
+    Ok((high << 8) | low)
+  }
+
+  fn next_32_bits(&mut self) -> Result<u32, VmError> {
+    let high = self.next_16_bits()? as u32;
+    let low = self.next_16_bits()? as u32;
+    Ok((high << 16) | low)
+  }
+
+  fn next_f64(&mut self) -> Result<f64, VmError> {
+    let mut bytes = [0; 8];
+    for byte in bytes.iter_mut() {
+      *byte = self.next_8_bits()?;
+    }
+    Ok(f64::from_be_bytes(bytes))
+  }
+
+  fn next_register(&mut self) -> Result<usize, VmError> {
+    let register = self.next_8_bits()?;
+    if register as usize >= self.registers.len() {
+      return Err(VmError::RegisterOutOfRange {
+        pc: self.instruction_pc,
+        register,
+      });
+    }
+    Ok(register as usize)
+  }
+
+  fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+    if target < 0 {
+      return Err(VmError::PcOutOfBounds {
+        pc: self.instruction_pc,
+        target,
+      });
+    }
+    self.pc = target as usize;
+    Ok(())
+  }
+
+  /// Decodes the `$base #offset` operands of a heap access and returns the
+  /// range of `size` bytes it touches.
+  fn heap_range(&mut self, size: usize) -> Result<Range<usize>, VmError> {
+    let base = self.registers[self.next_register()?] as i64;
+    let address = base + self.next_8_bits()? as i64;
+    if address < 0 || address as usize + size > self.heap.len() {
+      return Err(VmError::HeapOutOfBounds {
+        pc: self.instruction_pc,
+        address,
+      });
+    }
+    Ok(address as usize..address as usize + size)
+  }
+
+  /// Stores the wrapped result of an arithmetic instruction and its flags, or
+  /// traps without changing anything when it overflowed and traps are enabled.
+  fn set_arithmetic(
+    &mut self,
+    register: usize,
+    (result, flags): (i32, Flags),
+  ) -> Result<(), VmError> {
+    if flags.overflow && self.trap_overflow {
+      return Err(VmError::ArithmeticOverflow {
+        pc: self.instruction_pc,
+      });
+    }
+    self.registers[register] = result;
+    self.flags = flags;
+    Ok(())
+  }
+
+  /// Records the heap bytes in `range` before an instruction overwrites
+  /// them, when keeping history.
+  fn save_heap(&mut self, range: Range<usize>) {
+    if self.history_limit > 0 {
+      self.heap_undo = Some(history::HeapUndo::new(&self.heap, range));
+    }
+  }
+
+  fn push(&mut self, value: i32) -> Result<(), VmError> {
+    if self.stack.len() >= self.stack_limit {
+      return Err(VmError::StackOverflow {
+        pc: self.instruction_pc,
+      });
+    }
+    self.stack.push(value);
+    Ok(())
+  }
+
+  fn pop(&mut self) -> Result<i32, VmError> {
+    self.stack.pop().ok_or(VmError::StackUnderflow {
+      pc: self.instruction_pc,
+    })
+  }
+
+  fn decode_opcode(&mut self) -> Result<Opcode, VmError> {
+    let byte = self.next_8_bits()?;
+    match Opcode::from(byte) {
+      Opcode::IGL => Err(VmError::IllegalOpcode {
+        pc: self.instruction_pc,
+        byte,
+      }),
+      opcode => Ok(opcode),
+    }
+  }
+
+  fn execute_instruction(&mut self) -> Result<Option<ExitReason>, VmError> {
+    let recording = self.history_limit > 0;
+    let opcode = match self.current_opcode() {
+      Some(opcode) if self.tracer.is_some() || self.profile.is_some() || recording => opcode,
+      _ => return self.execute_untraced(),
+    };
+    let undo = if recording {
+      Some(history::UndoEntry::before(self))
+    } else {
+      None
+    };
+    self.heap_undo = None;
+    let pc = self.pc;
+    let traced = self
+      .tracer
+      .as_ref()
+      .is_some_and(|tracer| tracer.filter().matches(pc, opcode));
+    let before = self.registers;
+    let before_float = self.float_registers;
+    let started = Instant::now();
+    let result = self.execute_untraced();
+    let elapsed = started.elapsed();
+    // Recorded even when the instruction traps, to undo what it changed
+    // before failing
+    if let Some(mut entry) = undo {
+      entry.registers = changed_registers(&before, &self.registers)
+        .map(|register| (register, before[register]))
+        .collect();
+      entry.float_registers = changed_float_registers(&before_float, &self.float_registers)
+        .map(|register| (register, before_float[register]))
+        .collect();
+      entry.heap = self.heap_undo.take();
+      if self.history.len() == self.history_limit {
+        self.history.pop_front();
+      }
+      self.history.push_back(entry);
+    }
+    if result.is_ok() {
+      if let Some(profile) = &mut self.profile {
+        profile.record(pc, opcode, elapsed, self.pc);
+      }
+    }
+    if traced {
+      let error = result.as_ref().err().map(VmError::to_string);
+      let recorded = self.trace(pc, &before, &before_float, error);
+      // A trap is reported rather than a failure to write its trace
+      if result.is_ok() {
+        recorded?;
+      }
+    }
+    result
+  }
+
+  /// Records the instruction at `pc`, which just ran with the registers
+  /// holding `before` and `before_float` and failed with `error`, if any.
+  fn trace(
+    &mut self,
+    pc: usize,
+    before: &[i32],
+    before_float: &[f64],
+    error: Option<String>,
+  ) -> Result<(), VmError> {
+    let instruction = match disassemble_instruction(&self.program, pc) {
+      Ok(text) => text,
+      Err(e) => e.to_string(),
+    };
+    let registers = changed_registers(before, &self.registers)
+      .map(|register| trace::RegisterChange {
+        register,
+        value: self.registers[register],
+      })
+      .collect();
+    let float_registers = changed_float_registers(before_float, &self.float_registers)
+      .map(|register| trace::FloatRegisterChange {
+        register,
+        value: self.float_registers[register],
+      })
+      .collect();
+    let event = TraceEvent {
+      pc,
+      instruction,
+      registers,
+      float_registers,
+      flags: self.flags,
+      error,
+    };
+    if let Some(tracer) = &mut self.tracer {
+      tracer.record(&event).map_err(|e| VmError::OutputError {
+        pc,
+        message: e.to_string(),
+      })?;
+    }
+    Ok(())
+  }
+
+  fn execute_untraced(&mut self) -> Result<Option<ExitReason>, VmError> {
+    if self.pc == self.program.len() {
+      return Ok(Some(ExitReason::EndOfProgram));
+    }
+    if self.pc > self.program.len() {
+      // Reported against the instruction that moved the pc out of bounds
+      return Err(VmError::PcOutOfBounds {
+        pc: self.instruction_pc,
+        target: self.pc as i64,
+      });
+    }
+
+    self.instruction_pc = self.pc;
+    let opcode = self.decode_opcode()?;
+    // Operands are decoded by the handlers, the table gives where the next
+    // instruction starts
+    let next_pc = self.instruction_pc + opcode.width();
+    let mut jump = None;
+    match opcode {
+      // Machine halting
+      Opcode::HLT => {
+        return Ok(Some(ExitReason::Halted));
+      }
+
+      // Register load
+      Opcode::LOAD => {
+        let register = self.next_register()?;
+        let number = self.next_16_bits()?;
+        self.registers[register] = number as i32;
+      }
+      Opcode::LDI => {
+        let register = self.next_register()?;
+        self.registers[register] = self.next_32_bits()? as i32;
+      }
+      Opcode::MOV => {
+        let dst = self.next_register()?;
+        self.registers[dst] = self.registers[self.next_register()?];
+      }
+
+      // Arithmetic ops
+      Opcode::ADD => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        self.set_arithmetic(r3, Flags::add(r1, r2))?;
+      }
+      Opcode::SUB => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        self.set_arithmetic(r3, Flags::sub(r1, r2))?;
+      }
+      Opcode::MUL => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        self.set_arithmetic(r3, Flags::mul(r1, r2))?;
+      }
+      Opcode::DIV => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        if r2 == 0 {
+          return Err(VmError::DivideByZero {
+            pc: self.instruction_pc,
+          });
+        }
+        let (result, overflow) = r1.overflowing_div(r2);
+        let flags = Flags {
+          overflow,
+          ..Flags::from_result(result)
+        };
+        self.set_arithmetic(r3, (result, flags))?;
+        self.reminder = r1.wrapping_rem(r2) as u32;
+      }
+      Opcode::MOD => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        if r2 == 0 {
+          return Err(VmError::DivideByZero {
+            pc: self.instruction_pc,
+          });
+        }
+        let (result, overflow) = r1.overflowing_rem(r2);
+        let flags = Flags {
+          overflow,
+          ..Flags::from_result(result)
+        };
+        self.set_arithmetic(r3, (result, flags))?;
+      }
+      Opcode::INC => {
+        let r = self.next_register()?;
+        self.set_arithmetic(r, Flags::add(self.registers[r], 1))?;
+      }
+      Opcode::DEC => {
+        let r = self.next_register()?;
+        self.set_arithmetic(r, Flags::sub(self.registers[r], 1))?;
+      }
+
+      // Bitwise operations, shift amounts are taken modulo 32
+      Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+        let r1 = self.registers[self.next_register()?];
+        let r2 = self.registers[self.next_register()?];
+        let r3 = self.next_register()?;
+        let result = match opcode {
+          Opcode::AND => r1 & r2,
+          Opcode::OR => r1 | r2,
+          Opcode::XOR => r1 ^ r2,
+          Opcode::SHL => r1.wrapping_shl(r2 as u32),
+          Opcode::SHR => (r1 as u32).wrapping_shr(r2 as u32) as i32,
+          _ => r1.wrapping_shr(r2 as u32),
+        };
+        self.registers[r3] = result;
+        self.flags = Flags::from_result(result);
+      }
+      Opcode::NOT => {
+        let result = !self.registers[self.next_register()?];
+        let r = self.next_register()?;
+        self.registers[r] = result;
+        self.flags = Flags::from_result(result);
+      }
+
+      // Floating point
+      Opcode::LOADF => {
+        let r = self.next_register()?;
+        self.float_registers[r] = self.next_f64()?;
+      }
+      Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+        let f1 = self.float_registers[self.next_register()?];
+        let f2 = self.float_registers[self.next_register()?];
+        let f3 = self.next_register()?;
+        self.float_registers[f3] = match opcode {
+          Opcode::ADDF => f1 + f2,
+          Opcode::SUBF => f1 - f2,
+          Opcode::MULF => f1 * f2,
+          _ => f1 / f2,
+        };
+      }
+      Opcode::CMPF => {
+        let l = self.float_registers[self.next_register()?];
+        let r = self.float_registers[self.next_register()?];
+        self.flags = Flags::compare_floats(l, r);
+      }
+      Opcode::ITOF => {
+        let value = self.registers[self.next_register()?];
+        self.float_registers[self.next_register()?] = value as f64;
+      }
+      Opcode::FTOI => {
+        // Truncates towards zero, saturating at the i32 bounds, NaN gives 0
+        let value = self.float_registers[self.next_register()?];
+        self.registers[self.next_register()?] = value as i32;
+      }
+
+      // Jumps
+      Opcode::JMP => {
+        let target = self.next_16_bits()?;
+        jump = Some(target as i64);
+      }
+      Opcode::JMPF => {
+        let offset = self.registers[self.next_register()?];
+        jump = Some(self.pc as i64 + offset as i64);
+      }
+      Opcode::JMPB => {
+        let offset = self.registers[self.next_register()?];
+        jump = Some(self.pc as i64 - offset as i64);
+      }
+
+      // Logic comparisons
+      // The older comparisons are kept as aliases of `CMP`: the jumps test
+      // the operands (`gt $a $b` then `jgt`), not whether the comparison held
+      Opcode::CMP
+      | Opcode::EQ
+      | Opcode::NEQ
+      | Opcode::GT
+      | Opcode::LT
+      | Opcode::GTE
+      | Opcode::LTE => {
+        let l = self.registers[self.next_register()?];
+        let r = self.registers[self.next_register()?];
+        self.flags = Flags::sub(l, r).1;
+      }
+      Opcode::JEQ
+      | Opcode::JNE
+      | Opcode::JGT
+      | Opcode::JLT
+      | Opcode::JGE
+      | Opcode::JLE
+      | Opcode::JGTU
+      | Opcode::JLTU
+      | Opcode::JGEU
+      | Opcode::JLEU
+      | Opcode::JC
+      | Opcode::JO => {
+        if self.flags.allows(opcode) {
+          let target = self.next_16_bits()?;
+          jump = Some(target as i64);
+        }
+      }
+
+      // Memory
+      Opcode::ALOC => {
+        let bytes = self.registers[self.next_register()?];
+        let exhausted = VmError::HeapExhausted {
+          pc: self.instruction_pc,
+          requested: bytes,
+        };
+        if bytes < 0 {
+          return Err(exhausted);
+        }
+        let new_end = self.heap.len() + bytes as usize;
+        // Checked before reserving, so an over-limit request takes no memory
+        if self.heap_limit.is_some_and(|limit| new_end > limit) {
+          // Leave the pc on the failed allocation
+          self.pc = self.instruction_pc;
+          return Ok(Some(ExitReason::MemoryLimit));
+        }
+        if self.heap.try_reserve(bytes as usize).is_err() {
+          return Err(exhausted);
+        }
+        self.save_heap(self.heap.len()..self.heap.len());
+        self.heap.resize(new_end, 0);
+      }
+      Opcode::FREE => {
+        let bytes = self.registers[self.next_register()?];
+        if bytes < 0 || bytes as usize > self.heap.len() {
+          return Err(VmError::InvalidFree {
+            pc: self.instruction_pc,
+            requested: bytes,
+          });
+        }
+        let new_end = self.heap.len() - bytes as usize;
+        self.save_heap(new_end..self.heap.len());
+        self.heap.truncate(new_end);
+        self.heap.shrink_to_fit();
+      }
+      Opcode::LDB => {
+        let r = self.next_register()?;
+        let range = self.heap_range(1)?;
+        self.registers[r] = self.heap[range.start] as i32;
+      }
+      Opcode::STB => {
+        let value = self.registers[self.next_register()?];
+        let range = self.heap_range(1)?;
+        self.save_heap(range.clone());
+        self.heap[range.start] = value as u8;
+      }
+      Opcode::LDW => {
+        let r = self.next_register()?;
+        let range = self.heap_range(4)?;
+        self.registers[r] = LittleEndian::read_i32(&self.heap[range]);
+      }
+      Opcode::STW => {
+        let value = self.registers[self.next_register()?];
+        let range = self.heap_range(4)?;
+        self.save_heap(range.clone());
+        LittleEndian::write_i32(&mut self.heap[range], value);
+      }
+
+      // Subroutines and stack
+      Opcode::CALL => {
+        let target = self.next_16_bits()?;
+        self.push(next_pc as i32)?;
+        jump = Some(target as i64);
+      }
+      Opcode::RET => {
+        let target = *self.stack.last().ok_or(VmError::StackUnderflow {
+          pc: self.instruction_pc,
+        })?;
+        // Checked before popping, so a bad return leaves the stack alone
+        self.jump_to(target as i64)?;
+        self.stack.pop();
+        return Ok(None);
+      }
+      Opcode::PUSH => {
+        let value = self.registers[self.next_register()?];
+        self.push(value)?;
+      }
+      Opcode::POP => {
+        let r = self.next_register()?;
+        self.registers[r] = self.pop()?;
+      }
+
+      // Display
+      Opcode::PRTS => {
+        let offset = self.next_16_bits()? as usize;
+        let text = self.ro_string(offset)?.to_vec();
+        let pc = self.instruction_pc;
+        self
+          .output
+          .0
+          .write_all(&text)
+          .and_then(|_| self.output.0.flush())
+          .map_err(|e| VmError::OutputError {
+            pc,
+            message: e.to_string(),
+          })?;
+      }
+
+      // Host functions
+      Opcode::SYSCALL => {
+        let id = self.next_16_bits()?;
+        let pc = self.instruction_pc;
+        // Host functions may write anywhere in the heap
+        self.save_heap(0..self.heap.len());
+        let host_fn = self
+          .host_fns
+          .0
+          .get_mut(&id)
+          .ok_or(VmError::UnknownSyscall { pc, id })?;
+        let mut context = VmContext {
+          registers: &mut self.registers,
+          heap: &mut self.heap,
+          ro_data: &self.ro_data,
+          pc,
+          syscall: id,
+          output: &mut *self.output.0,
+          input: &mut *self.input.0,
+        };
+        host_fn(&mut context)?;
+      }
+
+      // Invalid code, already rejected while decoding
+      Opcode::IGL => unreachable!(),
+    }
+    match jump {
+      Some(target) => self.jump_to(target)?,
+      None => self.pc = next_pc,
+    }
+    Ok(None)
+  }
+}
+
+/// Indexes of the registers that differ between `before` and `after`.
+fn changed_registers<'a>(before: &'a [i32], after: &'a [i32]) -> impl Iterator<Item = usize> + 'a {
+  (0..before.len()).filter(move |r| before[*r] != after[*r])
+}
+
+/// Indexes of the float registers whose bits differ, so that a NaN written
+/// over a NaN does not count as a change.
+fn changed_float_registers<'a>(
+  before: &'a [f64],
+  after: &'a [f64],
+) -> impl Iterator<Item = usize> + 'a {
+  (0..before.len()).filter(move |r| before[*r].to_bits() != after[*r].to_bits())
+}
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::Assembler;
+  use crate::pie::PieHeaderError;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+
+  /// Writer whose contents can still be inspected after handing it to a VM.
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn get_test_vm() -> VM {
+    VM::new()
+  }
+
+  #[test]
+  fn test_opcode_hlt() {
+    let mut test_vm = get_test_vm();
+    let test_bytes = vec![0, 0, 0, 0];
+    test_vm.program = test_bytes;
+    assert_eq!(test_vm.run_once(), Ok(Some(ExitReason::Halted)));
+    assert_eq!(test_vm.pc, 1);
+  }
+  #[test]
+  fn test_opcode_igl() {
+    let mut test_vm = get_test_vm();
+    let test_bytes = vec![200, 0, 0, 0];
+    test_vm.program = test_bytes;
+    assert_eq!(
+      test_vm.run_once(),
+      Err(VmError::IllegalOpcode { pc: 0, byte: 200 })
+    );
+    assert_eq!(test_vm.pc, 1);
+  }
+
+  #[test]
+  fn test_opcode_load() {
+    let mut test_vm = get_test_vm();
+    test_vm.program = vec![1, 0, 2, 246];
+    test_vm.run_once().unwrap();
+    assert_eq!(test_vm.registers[0], 758);
+  }
+
+  #[test]
+  fn test_opcode_add() {
+    let mut test_vm = get_test_vm();
+    test_vm.program = vec![2, 0, 1, 2];
+    test_vm.registers[0] = 2;
+    test_vm.registers[1] = 3;
+    test_vm.run_once().unwrap();
+    assert_eq!(test_vm.registers[2], 5);
+  }
+
+  #[test]
+  fn test_opcode_sub() {
+    let mut test_vm = get_test_vm();
+    test_vm.program = vec![3, 0, 1, 2];
+    test_vm.registers[0] = 8;
+    test_vm.registers[1] = 3;
+    test_vm.run_once().unwrap();
+    assert_eq!(test_vm.registers[2], 5);
+  }
+
+  #[test]
+  fn test_opcode_mul() {
+    let mut test_vm = get_test_vm();
+    test_vm.program = vec![4, 0, 1, 2];
+    test_vm.registers[0] = 8;
+    test_vm.registers[1] = 3;
+    test_vm.run_once().unwrap();
+    assert_eq!(test_vm.registers[2], 24);
+  }
+
+  #[test]
+  fn test_opcode_div() {
+    let mut test_vm = get_test_vm();
+    test_vm.program = vec![5, 0, 1, 2];
+    test_vm.registers[0] = 11;
+    test_vm.registers[1] = 3;
+    test_vm.run_once().unwrap();
+    assert_eq!(test_vm.registers[2], 3);
+    assert_eq!(test_vm.reminder, 2);
+  }
+
+  #[test]
+  fn test_opcode_jmp() {
+    let mut vm = get_test_vm();
+    vm.program = vec![6, 0, 8, 0];
+    vm.registers[8] = 2;
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 8);
+  }
+
+  #[test]
+  fn test_opcode_jmp_f_b() {
+    let mut vm = get_test_vm();
+    vm.program = vec![7, 0, 0, 0, 8, 1];
+    vm.registers[0] = 2;
+    vm.registers[1] = 3;
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 4);
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 3);
+  }
+
+  #[test]
+  fn test_opcode_eq() {
+    let mut vm = get_test_vm();
+    vm.program = vec![9, 0, 1, 0, 9, 0, 2];
+    vm.registers[0] = 5;
+    vm.registers[1] = 5;
+    vm.registers[2] = 7;
+    vm.run_once().unwrap();
+    assert!(vm.flags.zero && vm.flags.allows(Opcode::JEQ));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JEQ) && vm.flags.allows(Opcode::JNE));
+  }
+
+  #[test]
+  fn test_opcode_neq() {
+    let mut vm = get_test_vm();
+    vm.program = vec![10, 0, 1, 0, 10, 0, 2];
+    vm.registers[0] = 3;
+    vm.registers[1] = 4;
+    vm.registers[2] = 3;
+    vm.run_once().unwrap();
+    assert!(!vm.flags.zero && vm.flags.allows(Opcode::JNE));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JNE));
+  }
+
+  #[test]
+  fn test_opcode_gt() {
+    let mut vm = get_test_vm();
+    vm.program = vec![11, 0, 1, 0, 11, 0, 2];
+    vm.registers[0] = 2;
+    vm.registers[1] = 1;
+    vm.registers[2] = 2;
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JGT) && vm.flags.allows(Opcode::JNE));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JGT) && !vm.flags.allows(Opcode::JNE));
+  }
+
+  #[test]
+  fn test_opcode_lt() {
+    let mut vm = get_test_vm();
+    vm.program = vec![12, 0, 1, 0, 12, 0, 2];
+    vm.registers[0] = 5;
+    vm.registers[1] = 10;
+    vm.registers[2] = 5;
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JLT));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JLT));
+  }
+
+  #[test]
+  fn test_opcode_gte() {
+    let mut vm = get_test_vm();
+    vm.program = vec![13, 0, 1, 0, 13, 0, 2, 0, 13, 0, 3, 0];
+    vm.registers[0] = 4;
+    vm.registers[1] = 4;
+    vm.registers[2] = 2;
+    vm.registers[3] = 7;
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JGE));
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JGE));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JGE));
+  }
+
+  #[test]
+  fn test_opcode_lte() {
+    let mut vm = get_test_vm();
+    vm.program = vec![14, 0, 1, 0, 14, 0, 2, 0, 14, 0, 3];
+    vm.registers[0] = 5;
+    vm.registers[1] = 5;
+    vm.registers[2] = 10;
+    vm.registers[3] = 2;
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JLE));
+    vm.run_once().unwrap();
+    assert!(vm.flags.allows(Opcode::JLE));
+    vm.run_once().unwrap();
+    assert!(!vm.flags.allows(Opcode::JLE));
+  }
+
+  #[test]
+  fn test_opcode_jeq() {
+    let mut vm = get_test_vm();
+    vm.program = vec![15, 0, 5, 0];
+    vm.flags.zero = true;
+    vm.registers[0] = 3;
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 5);
+  }
+
+  #[test]
+  fn test_opcode_cmp_signed_and_unsigned_jumps() {
+    let mut vm = get_test_vm();
+    // cmp $0 $1, then jlt, jgtu and jne to 20, which are only taken when the
+    // operands compare as -1 < 1 signed but 0xffffffff > 1 unsigned
+    vm.program = vec![30, 0, 1, 0, 33, 0, 20, 0];
+    vm.registers[0] = -1;
+    vm.registers[1] = 1;
+    vm.run_once().unwrap();
+    assert_eq!(
+      vm.flags,
+      Flags {
+        zero: false,
+        negative: true,
+        carry: false,
+        overflow: false,
+        unordered: false,
+      }
+    );
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 20);
+    for (opcode, taken) in &[
+      (Opcode::JNE, true),
+      (Opcode::JGT, false),
+      (Opcode::JLE, true),
+      (Opcode::JGTU, true),
+      (Opcode::JLTU, false),
+      (Opcode::JEQ, false),
+    ] {
+      vm.pc = 0;
+      vm.program = vec![30, 0, 1, 0, (*opcode).into(), 0, 20, 0];
+      vm.run_once().unwrap();
+      vm.run_once().unwrap();
+      assert_eq!(vm.pc == 20, *taken, "{:?}", opcode);
+    }
+  }
+
+  #[test]
+  fn test_arithmetic_sets_flags() {
+    let mut vm = get_test_vm();
+    // add $0 $1 $2, then jo to 20
+    vm.program = vec![2, 0, 1, 2, 41, 0, 20, 0];
+    vm.registers[0] = i32::MAX;
+    vm.registers[1] = 1;
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[2], i32::MIN);
+    assert!(vm.flags.overflow && vm.flags.negative && !vm.flags.carry);
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 20);
+  }
+
+  #[test]
+  fn test_trap_on_overflow() {
+    let mut vm = get_test_vm();
+    // mul $0 $1 $2, dec $0
+    vm.program = vec![4, 0, 1, 2, 18, 0, 0, 0];
+    vm.registers[0] = i32::MIN;
+    vm.registers[1] = 2;
+    vm.set_trap_on_overflow(true);
+    assert_eq!(vm.run_once(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+    assert_eq!(vm.registers[2], 0);
+    vm.pc = 4;
+    assert_eq!(vm.run_once(), Err(VmError::ArithmeticOverflow { pc: 4 }));
+    assert_eq!(vm.registers[0], i32::MIN);
+    vm.set_trap_on_overflow(false);
+    vm.pc = 4;
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[0], i32::MAX);
+    assert!(vm.flags.overflow);
+  }
+
+  #[test]
+  fn test_bitwise_opcodes() {
+    let mut vm = get_test_vm();
+    vm.registers[0] = -16;
+    vm.registers[1] = 0b1100;
+    vm.registers[2] = 2;
+    for (program, expected) in &[
+      (vec![42, 0, 1, 3], 0),
+      (vec![43, 0, 1, 3], -4),
+      (vec![44, 1, 2, 3], 0b1110),
+      (vec![45, 1, 3, 0], !0b1100),
+      (vec![46, 1, 2, 3], 0b110000),
+      (vec![47, 0, 2, 3], 0x3fff_fffc),
+      (vec![48, 0, 2, 3], -4),
+    ] {
+      vm.pc = 0;
+      vm.program = program.clone();
+      vm.run_once().unwrap();
+      assert_eq!(vm.registers[3], *expected, "{:?}", program);
+      assert_eq!(vm.flags.zero, *expected == 0);
+    }
+  }
+
+  #[test]
+  fn test_opcode_mod() {
+    let mut vm = get_test_vm();
+    vm.program = vec![49, 0, 1, 2, 49, 0, 3, 2];
+    vm.registers[0] = -7;
+    vm.registers[1] = 3;
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[2], -1);
+    assert_eq!(vm.run_once(), Err(VmError::DivideByZero { pc: 4 }));
+  }
+
+  #[test]
+  fn test_float_opcodes() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new()
+      .assemble(
+        "ldf $f0 #7.5\nldf $f1 #-2.0\naddf $f0 $f1 $f2\nmulf $f0 $f1 $f3\ndivf $f0 $f1 $f4\n\
+         subf $f1 $f0 $f5\ncmpf $f1 $f0\nftoi $f4 $0\nitof $0 $f6\nhlt",
+      )
+      .unwrap();
+    vm.set_history_limit(2);
+    vm.run().unwrap();
+    assert_eq!(
+      &vm.float_registers[..7],
+      &[7.5, -2.0, 5.5, -15.0, -3.75, -9.5, -3.0]
+    );
+    assert_eq!(vm.registers[0], -3);
+    assert!(vm.flags.allows(Opcode::JLT) && vm.flags.allows(Opcode::JLTU));
+    vm.step_back();
+    vm.step_back();
+    assert_eq!(vm.float_registers[6], 0.0);
+  }
+
+  #[test]
+  fn test_compare_floats_with_nan() {
+    let mut vm = get_test_vm();
+    // Every ordered jump falls through to `jne`, which skips the `inc $1`
+    let mut source =
+      String::from("ldf $f1 #1.0\nsubf $f0 $f0 $f0\ndivf $f0 $f0 $f0\ncmpf $f0 $f1\n");
+    for jump in [
+      "jeq", "jgt", "jlt", "jge", "jle", "jgtu", "jltu", "jgeu", "jleu", "jc",
+    ] {
+      source.push_str(&format!("{} @ordered\n", jump));
+    }
+    source.push_str("jne @unordered\ninc $1\nunordered: hlt\nordered: inc $0\nhlt");
+    vm.program = Assembler::new().assemble(&source).unwrap();
+    vm.run().unwrap();
+    assert!(vm.float_registers[0].is_nan());
+    assert!(vm.flags.unordered && vm.flags.overflow && !vm.flags.zero);
+    assert_eq!((vm.registers[0], vm.registers[1]), (0, 0));
+  }
+
+  #[test]
+  fn test_opcode_ldi_and_mov() {
+    let mut vm = get_test_vm();
+    vm.program = vec![58, 0, 0xff, 0xfe, 0x79, 0x60, 0, 0, 59, 1, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[0], -100_000);
+    assert_eq!(vm.pc, 8);
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[1], -100_000);
+  }
+
+  #[test]
+  fn test_opcode_aloc() {
+    let mut vm = get_test_vm();
+    vm.registers[0] = 1024;
+    vm.program = vec![16, 0, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.heap.len(), 1024);
+  }
+
+  #[test]
+  fn test_opcode_inc() {
+    let mut vm = get_test_vm();
+    vm.registers[0] = 1;
+    vm.program = vec![17, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[0], 2);
+  }
+
+  #[test]
+  fn test_opcode_dec() {
+    let mut vm = get_test_vm();
+    vm.registers[0] = 2;
+    vm.program = vec![18, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[0], 1);
+  }
+
+  #[test]
+  fn test_run_rejects_invalid_header() {
+    let mut vm = get_test_vm();
+    vm.program = vec![1, 0, 0, 10];
+    assert_eq!(
+      vm.run(),
+      Err(VmError::InvalidHeader(PieHeaderError::TooShort {
+        length: 4
+      }))
+    );
+  }
+
+  #[test]
+  fn test_run_reports_exit_reason() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new().assemble("ld $0 #1").unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::EndOfProgram));
+
+    vm.program = Assembler::new().assemble("ld $0 #1\nhlt").unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+  }
+
+  #[test]
+  fn test_register_out_of_range() {
+    let mut vm = get_test_vm();
+    vm.program = vec![1, 32, 0, 1];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::RegisterOutOfRange {
+        pc: 0,
+        register: 32
+      })
+    );
+  }
+
+  #[test]
+  fn test_divide_by_zero() {
+    let mut vm = get_test_vm();
+    vm.program = vec![2, 0, 1, 2, 5, 0, 1, 2];
+    vm.registers[0] = 11;
+    vm.run_once().unwrap();
+    assert_eq!(vm.run_once(), Err(VmError::DivideByZero { pc: 4 }));
+  }
+
+  #[test]
+  fn test_truncated_instruction() {
+    let mut vm = get_test_vm();
+    vm.program = vec![1, 0, 2];
+    assert_eq!(vm.run_once(), Err(VmError::TruncatedInstruction { pc: 0 }));
+  }
+
+  #[test]
+  fn test_jump_out_of_bounds() {
+    let mut vm = get_test_vm();
+    vm.program = vec![6, 1, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::PcOutOfBounds { pc: 0, target: 256 })
+    );
+
+    vm.program = vec![8, 0, 0, 0];
+    vm.pc = 0;
+    vm.registers[0] = 3;
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::PcOutOfBounds { pc: 0, target: -1 })
+    );
+  }
+
+  #[test]
+  fn test_opcode_aloc_negative() {
+    let mut vm = get_test_vm();
+    vm.registers[0] = -1;
+    vm.program = vec![16, 0, 0, 0];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::HeapExhausted {
+        pc: 0,
+        requested: -1
+      })
+    );
+  }
+
+  #[test]
+  fn test_opcode_prts() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    vm.set_output(Box::new(output.clone()));
+    vm.ro_data = b"hi\0there\0".to_vec();
+    vm.program = vec![19, 0, 3, 0, 19, 0, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 4);
+    vm.run_once().unwrap();
+    assert_eq!(output.0.borrow().as_slice(), b"therehi");
+  }
+
+  #[test]
+  fn test_opcode_prts_invalid_offset() {
+    let mut vm = get_test_vm();
+    vm.ro_data = b"hi".to_vec();
+    vm.program = vec![19, 0, 0, 0, 19, 0, 9, 0];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::InvalidStringOffset { pc: 0, offset: 0 })
+    );
+  }
+
+  #[test]
+  fn test_run_string_program() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    vm.set_output(Box::new(output.clone()));
+    let source =
+      ".data\nhello: .asciiz 'Hello'\nworld: .asciiz 'world'\n.code\nprts @world\nprts @hello\nhlt";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert_eq!(vm.get_ro_data(), b"Hello\0world\0");
+    assert_eq!(output.0.borrow().as_slice(), b"worldHello");
+  }
+
+  #[test]
+  fn test_run_rejects_truncated_ro_data() {
+    let mut vm = get_test_vm();
+    let mut program = Assembler::new().assemble("hlt").unwrap();
+    program[12] = 200;
+    vm.program = program;
+    assert_eq!(
+      vm.run(),
+      Err(VmError::InvalidHeader(PieHeaderError::SectionMismatch {
+        section: "symbol table"
+      }))
+    );
+  }
+
+  #[test]
+  fn test_run_starts_at_entry_point() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new()
+      .assemble("ld $0 #1\nmain: ld $1 #2\nhlt")
+      .unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert_eq!(vm.registers[0], 0);
+    assert_eq!(vm.registers[1], 2);
+  }
+
+  #[test]
+  fn test_opcode_call_ret() {
+    let mut vm = get_test_vm();
+    vm.program = vec![20, 0, 8, 0, 0, 0, 0, 0, 21, 0, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 8);
+    assert_eq!(vm.stack, vec![4]);
+    vm.run_once().unwrap();
+    assert_eq!(vm.pc, 4);
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn test_opcode_push_pop() {
+    let mut vm = get_test_vm();
+    vm.program = vec![22, 0, 0, 0, 23, 1, 0, 0];
+    vm.registers[0] = -7;
+    vm.run_once().unwrap();
+    assert_eq!(vm.stack, vec![-7]);
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[1], -7);
+    assert_eq!(vm.pc, 8);
+  }
+
+  #[test]
+  fn test_stack_overflow_and_underflow() {
+    let mut vm = get_test_vm();
+    vm.set_stack_limit(1);
+    vm.program = vec![22, 0, 0, 0, 22, 0, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.run_once(), Err(VmError::StackOverflow { pc: 4 }));
+
+    vm.program = vec![21, 0, 0, 0];
+    vm.pc = 0;
+    vm.stack.clear();
+    assert_eq!(vm.run_once(), Err(VmError::StackUnderflow { pc: 0 }));
+  }
+
+  #[test]
+  fn test_run_subroutine_program() {
+    let mut vm = get_test_vm();
+    let source = "main: ld $0 #5\ncall @double\ncall @double\nhlt\ndouble: push $1\nadd $0 $0 $1\nld $0 #0\nadd $1 $0 $0\npop $1\nret";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    vm.registers[1] = 9;
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert_eq!(vm.registers[0], 20);
+    assert_eq!(vm.registers[1], 9);
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn test_opcode_store_load_byte() {
+    let mut vm = get_test_vm();
+    vm.heap = vec![0; 8];
+    vm.registers[0] = 0x1ff;
+    vm.registers[1] = 2;
+    vm.program = vec![25, 0, 1, 3, 24, 2, 1, 3];
+    vm.run_once().unwrap();
+    assert_eq!(vm.heap, vec![0, 0, 0, 0, 0, 0xff, 0, 0]);
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[2], 0xff);
+  }
+
+  #[test]
+  fn test_opcode_store_load_word() {
+    let mut vm = get_test_vm();
+    vm.heap = vec![0; 8];
+    vm.registers[0] = -2;
+    vm.program = vec![27, 0, 1, 4, 26, 2, 1, 4];
+    vm.run_once().unwrap();
+    assert_eq!(vm.heap, vec![0, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff]);
+    vm.run_once().unwrap();
+    assert_eq!(vm.registers[2], -2);
+  }
+
+  #[test]
+  fn test_heap_out_of_bounds() {
+    let mut vm = get_test_vm();
+    vm.heap = vec![0; 8];
+    vm.registers[1] = 6;
+    vm.program = vec![26, 0, 1, 0];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::HeapOutOfBounds { pc: 0, address: 6 })
+    );
+
+    vm.pc = 0;
+    vm.registers[1] = -1;
+    vm.program = vec![24, 0, 1, 0];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::HeapOutOfBounds { pc: 0, address: -1 })
+    );
+  }
+
+  #[test]
+  fn test_opcode_free() {
+    let mut vm = get_test_vm();
+    vm.heap = vec![0; 8];
+    vm.registers[0] = 6;
+    vm.program = vec![28, 0, 0, 0, 28, 0, 0, 0];
+    vm.run_once().unwrap();
+    assert_eq!(vm.heap.len(), 2);
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::InvalidFree {
+        pc: 4,
+        requested: 6
+      })
+    );
+  }
+
+  #[test]
+  fn test_run_heap_program() {
+    let mut vm = get_test_vm();
+    let source =
+      "ld $0 #8\naloc $0\nld $1 #1000\nstw $1 $2 #4\nldw $3 $2 #4\nstb $1 $2 #0\nldb $4 $2 #0\nhlt";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert_eq!(vm.registers[3], 1000);
+    assert_eq!(vm.registers[4], 1000 & 0xff);
+  }
+
+  #[test]
+  fn test_run_with_instruction_limit() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new()
+      .assemble("loop: inc $0\njmp @loop")
+      .unwrap();
+    let limits = RunLimits {
+      max_instructions: Some(10),
+      ..Default::default()
+    };
+    assert_eq!(vm.run_with_limits(limits), Ok(ExitReason::FuelExhausted));
+    assert_eq!(vm.registers[0], 5);
+  }
+
+  #[test]
+  fn test_run_with_deadline() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new().assemble("loop: jmp @loop").unwrap();
+    let limits = RunLimits {
+      deadline: Some(Instant::now()),
+      ..Default::default()
+    };
+    assert_eq!(vm.run_with_limits(limits), Ok(ExitReason::Timeout));
+  }
+
+  #[test]
+  fn test_run_with_heap_limit() {
+    let mut vm = get_test_vm();
+    let source = "ld $0 #64\nloop: aloc $0\njmp @loop";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    let limits = RunLimits {
+      max_heap_bytes: Some(200),
+      ..Default::default()
+    };
+    assert_eq!(
+      vm.run_with_limits(limits.clone()),
+      Ok(ExitReason::MemoryLimit)
+    );
+    assert_eq!(vm.heap.len(), 192);
+    assert_eq!(vm.pc, 68);
+
+    let mut vm = get_test_vm();
+    let source = "ldi $0 #1600000000\naloc $0";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    assert_eq!(vm.run_with_limits(limits), Ok(ExitReason::MemoryLimit));
+    assert_eq!(vm.heap.capacity(), 0);
+  }
+
+  #[test]
+  fn test_opcode_syscall() {
+    let mut vm = get_test_vm();
+    vm.register_host_fn(
+      7,
+      Box::new(|context| {
+        context.registers[1] = context.registers[0] * 2;
+        Ok(())
+      }),
+    );
+    vm.registers[0] = 21;
+    vm.program = vec![29, 0, 7, 0];
+    assert_eq!(vm.run_once(), Ok(None));
+    assert_eq!(vm.registers[1], 42);
+    assert_eq!(vm.pc, 4);
+  }
+
+  #[test]
+  fn test_opcode_syscall_errors() {
+    let mut vm = get_test_vm();
+    vm.program = vec![29, 0, 7, 0];
+    assert_eq!(vm.run_once(), Err(VmError::UnknownSyscall { pc: 0, id: 7 }));
+
+    let mut vm = get_test_vm();
+    vm.register_host_fn(7, Box::new(|context| Err(context.error("nope"))));
+    vm.program = vec![29, 0, 7, 0];
+    assert_eq!(
+      vm.run_once(),
+      Err(VmError::HostError {
+        pc: 0,
+        syscall: 7,
+        message: "nope".to_string()
+      })
+    );
+  }
+
+  #[test]
+  fn test_run_std_host_fns() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    vm.set_output(Box::new(output.clone()));
+    vm.set_input(Box::new(io::Cursor::new(b"hello world\n".to_vec())));
+    vm.register_std_host_fns();
+    let source = "ld $0 #42\nsyscall #0\nld $2 #16\naloc $2\nld $0 #4\nld $1 #5\nsyscall #1\n\
+                  syscall #1\nld $0 #10\nsyscall #3\nsyscall #2\nhlt";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert_eq!(output.0.borrow().as_slice(), b"42\n");
+    assert_eq!(&vm.get_heap()[4..9], b"hello");
+    assert!(vm.registers[0] > 1_500_000_000);
+    assert!((0..1000).contains(&vm.registers[1]));
+  }
+
+  #[test]
+  fn test_random_host_fn_respects_bound() {
+    let mut vm = get_test_vm();
+    vm.register_host_fn(3, host::random(12345));
+    vm.program = vec![29, 0, 3, 0];
+    for _ in 0..100 {
+      vm.registers[0] = 6;
+      vm.pc = 0;
+      assert_eq!(vm.run_once(), Ok(None));
+      assert!((0..6).contains(&vm.registers[0]));
+    }
+  }
+
+  #[test]
+  fn test_resume_stops_at_breakpoints() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new()
+      .assemble("ld $0 #3\nloop: dec $0\neq $0 $1\njeq @end\njmp @loop\nend: hlt")
+      .unwrap();
+    vm.start().unwrap();
+    assert!(vm.add_breakpoint(68));
+    assert!(!vm.add_breakpoint(68));
+    assert_eq!(vm.resume(RunLimits::default()), Ok(ExitReason::Breakpoint));
+    assert_eq!((vm.pc, vm.registers[0]), (68, 3));
+    assert_eq!(vm.resume(RunLimits::default()), Ok(ExitReason::Breakpoint));
+    assert_eq!((vm.pc, vm.registers[0]), (68, 2));
+    assert!(vm.remove_breakpoint(68));
+    assert_eq!(vm.resume(RunLimits::default()), Ok(ExitReason::Halted));
+    assert_eq!(vm.registers[0], 0);
+  }
+
+  #[test]
+  fn test_step() {
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new().assemble("inc $0\ninc $0\nhlt").unwrap();
+    vm.start().unwrap();
+    vm.add_breakpoint(72);
+    assert_eq!(vm.step(), StepResult::Continue { pc: 68 });
+    assert_eq!(vm.step(), StepResult::Breakpoint { pc: 72 });
+    assert_eq!(vm.step(), StepResult::Exited(ExitReason::Halted));
+    assert_eq!(vm.registers[0], 2);
+
+    vm.program = vec![200, 0, 0, 0];
+    vm.pc = 0;
+    assert_eq!(
+      vm.step(),
+      StepResult::Trapped(VmError::IllegalOpcode { pc: 0, byte: 200 })
+    );
+  }
+
+  #[test]
+  fn test_step_over_and_out() {
+    let mut vm = get_test_vm();
+    let source = "main: call @sub\nhlt\nsub: inc $0\ncall @leaf\ninc $0\nret\nleaf: inc $1\nret";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    vm.start().unwrap();
+    assert_eq!(vm.step_over(), StepResult::Continue { pc: 68 });
+    assert_eq!((vm.registers[0], vm.registers[1]), (2, 1));
+
+    vm.start().unwrap();
+    vm.step();
+    vm.step();
+    assert_eq!(vm.pc, 76);
+    assert_eq!(vm.step_out(), StepResult::Continue { pc: 68 });
+    assert_eq!(vm.get_stack(), &[] as &[i32]);
+
+    vm.start().unwrap();
+    vm.add_breakpoint(92);
+    assert_eq!(vm.step_over(), StepResult::Breakpoint { pc: 92 });
+  }
+
+  #[test]
+  fn test_trace_to_json_lines() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    vm.set_tracer(Tracer::with_sink(
+      TraceFilter::default(),
+      Box::new(output.clone()),
+    ));
+    vm.program = Assembler::new()
+      .assemble("ld $0 #5\nld $1 #5\neq $0 $1\nhlt")
+      .unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    let trace = String::from_utf8(output.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+      lines[0],
+      r#"{"pc":64,"instruction":"ld $0 #5","registers":[{"register":0,"value":5}],"flags":{"zero":false,"negative":false,"carry":false,"overflow":false,"unordered":false}}"#
+    );
+    assert_eq!(
+      lines[2],
+      r#"{"pc":72,"instruction":"eq $0 $1","registers":[],"flags":{"zero":true,"negative":false,"carry":false,"overflow":false,"unordered":false}}"#
+    );
+  }
+
+  #[test]
+  fn test_trace_filter() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    let filter = TraceFilter {
+      pc_range: Some(64..76),
+      opcodes: Some(vec![Opcode::INC].into_iter().collect()),
+    };
+    vm.set_tracer(Tracer::with_sink(filter, Box::new(output.clone())));
+    vm.program = Assembler::new()
+      .assemble("inc $0\ndec $0\ninc $0\ninc $0")
+      .unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::EndOfProgram));
+    let trace = String::from_utf8(output.0.borrow().clone()).unwrap();
+    let pcs: Vec<&str> = trace.lines().map(|l| &l[6..8]).collect();
+    assert_eq!(pcs, vec!["64", "72"]);
+    assert!(vm.take_tracer().is_some());
+  }
+
+  #[test]
+  fn test_trace_trapping_instruction() {
+    let mut vm = get_test_vm();
+    let output = SharedBuffer::default();
+    vm.set_tracer(Tracer::with_sink(
+      TraceFilter::default(),
+      Box::new(output.clone()),
+    ));
+    vm.program = Assembler::new()
+      .assemble("ld $0 #5\ndiv $0 $1 $2\nhlt")
+      .unwrap();
+    assert_eq!(vm.run(), Err(VmError::DivideByZero { pc: 68 }));
+    let trace = String::from_utf8(output.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with(r#"{"pc":68,"instruction":"div $0 $1 $2","#));
+    assert!(lines[1].ends_with(r#","error":"division by zero at pc 68"}"#));
+  }
+
+  #[test]
+  fn test_profile() {
+    let mut vm = get_test_vm();
+    let mut asm = Assembler::new();
+    let source =
+      "main: ld $0 #3\ncall @sub\nhlt\nsub: inc $1\nloop: dec $0\ngt $0 $2\njgt @loop\nret";
+    vm.program = asm.assemble(source).unwrap();
+    vm.enable_profiling();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    let profile = vm.take_profile().unwrap();
+    assert_eq!(profile.total(), 14);
+    assert_eq!(profile.pc_counts[&80], 3);
+    assert_eq!(profile.opcode_counts[&Opcode::DEC], 3);
+    assert!(profile.opcode_time.contains_key(&Opcode::HLT));
+
+    let labels = profile.label_counts(&asm.symbols);
+    let labels: Vec<(&str, u64)> = labels.iter().map(|(l, c)| (l.as_str(), *c)).collect();
+    assert_eq!(labels, vec![("loop", 10), ("main", 3), ("sub", 1)]);
+    assert_eq!(profile.folded_stacks(&asm.symbols), "main;sub 11\nmain 3\n");
+    let report = profile.report(&asm.symbols);
+    assert!(report.starts_with("Instructions executed: 14\n"));
+    assert!(report.contains("0084 loop+4"));
+    assert!(vm.get_profile().is_none());
+  }
+
+  #[test]
+  fn test_snapshot_and_resume() {
+    let source =
+      "ld $1 #50\nld $2 #8\naloc $2\nloop: inc $0\ncall @store\nneq $0 $1\njne @loop\nhlt\n\
+                  store: stw $0 $3 #4\nret";
+    let mut expected = get_test_vm();
+    expected.program = Assembler::new().assemble(source).unwrap();
+    assert_eq!(expected.run(), Ok(ExitReason::Halted));
+
+    let mut vm = get_test_vm();
+    vm.program = Assembler::new().assemble(source).unwrap();
+    let limits = RunLimits {
+      max_instructions: Some(30),
+      ..Default::default()
+    };
+    assert_eq!(vm.run_with_limits(limits), Ok(ExitReason::FuelExhausted));
+    let snapshot = Snapshot::from_bytes(&vm.snapshot().to_bytes()).unwrap();
+
+    let mut resumed = get_test_vm();
+    resumed.restore(snapshot);
+    assert_eq!(resumed.resume(RunLimits::default()), Ok(ExitReason::Halted));
+    assert_eq!(resumed.snapshot(), expected.snapshot());
+    assert_eq!(&resumed.get_heap()[4..8], &[50, 0, 0, 0]);
+  }
+
+  #[test]
+  fn test_step_back() {
+    let mut vm = get_test_vm();
+    vm.set_history_limit(100);
+    let source = "ld $0 #8\naloc $0\nld $1 #7\nstw $1 $2 #4\ncall @sub\nhlt\nsub: push $1\npop $3\neq $1 $3\nfree $0\nret";
+    vm.program = Assembler::new().assemble(source).unwrap();
+    vm.start().unwrap();
+    let mut states = vec![];
+    loop {
+      states.push(vm.snapshot());
+      if let StepResult::Exited(_) = vm.step() {
+        break;
+      }
+    }
+    assert_eq!(vm.history_len(), 11);
+    while let Some(expected) = states.pop() {
+      assert!(vm.step_back());
+      assert_eq!(vm.snapshot(), expected);
+    }
+    assert!(!vm.step_back());
+  }
+
+  #[test]
+  fn test_history_limit() {
+    let mut vm = get_test_vm();
+    vm.set_history_limit(2);
+    vm.program = Assembler::new().assemble("inc $0\ninc $0\ninc $0").unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::EndOfProgram));
+    assert_eq!(vm.history_len(), 2);
+    assert!(vm.step_back());
+    assert!(vm.step_back());
+    assert!(!vm.step_back());
+    assert_eq!((vm.registers[0], vm.pc), (1, 68));
+
+    vm.run().unwrap();
+    vm.clear();
+    assert_eq!(vm.history_len(), 0);
+    assert!(!vm.step_back());
+  }
+
+  #[test]
+  fn test_step_back_over_trap() {
+    let mut vm = get_test_vm();
+    vm.set_history_limit(100);
+    vm.program = Assembler::new().assemble("dec $0\npush $0\nret").unwrap();
+    assert_eq!(vm.run(), Err(VmError::PcOutOfBounds { pc: 72, target: -1 }));
+    assert_eq!(vm.stack, vec![-1]);
+    assert_eq!(vm.history_len(), 3);
+    assert!(vm.step_back());
+    assert_eq!((vm.pc, vm.stack.clone()), (72, vec![-1]));
+    assert!(vm.step_back());
+    assert!(vm.stack.is_empty());
+  }
+
+  #[test]
+  fn test_reverse_continue() {
+    let mut vm = get_test_vm();
+    vm.set_history_limit(100);
+    vm.program = Assembler::new()
+      .assemble("ld $1 #3\nld $0 #1\nadd $0 $1 $2\ninc $0\nhlt")
+      .unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert!(vm.reverse_continue(2));
+    assert_eq!((vm.pc, vm.registers[0], vm.registers[2]), (72, 1, 0));
+    assert!(vm.reverse_continue(1));
+    assert_eq!(vm.pc, 64);
+    assert!(!vm.reverse_continue(1));
+
+    vm.program = Assembler::new()
+      .assemble("ldf $f1 #1.5\nld $0 #1\naddf $f1 $f1 $f2\ninc $0\nhlt")
+      .unwrap();
+    assert_eq!(vm.run(), Ok(ExitReason::Halted));
+    assert!(vm.reverse_continue_float(2));
+    assert_eq!((vm.pc, vm.float_registers[2]), (80, 0.0));
+    assert!(vm.reverse_continue_float(1));
+    assert_eq!(vm.pc, 64);
+    assert!(!vm.reverse_continue_float(1));
+  }
+}
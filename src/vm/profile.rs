@@ -0,0 +1,134 @@
+//! Instruction profiler. Counts executions per address, per opcode and per
+//! call stack, and measures the time spent in each opcode.
+
+use crate::assembler::SymbolTable;
+use crate::instruction::Opcode;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+  pub pc_counts: BTreeMap<usize, u64>,
+  pub opcode_counts: HashMap<Opcode, u64>,
+  pub opcode_time: HashMap<Opcode, Duration>,
+  /// Instructions executed per call stack, each frame being the address the
+  /// subroutine was entered at.
+  pub stack_counts: HashMap<Vec<usize>, u64>,
+  code_start: usize,
+  frames: Vec<usize>,
+}
+
+impl Profile {
+  /// Prepares for a run of code starting at `code_start`, entering at
+  /// `entry_point`.
+  pub(super) fn start(&mut self, code_start: usize, entry_point: usize) {
+    self.code_start = code_start;
+    self.frames = vec![entry_point];
+  }
+
+  /// Records the instruction at `pc`, which took `elapsed` and moved the pc
+  /// to `next_pc`.
+  pub(super) fn record(&mut self, pc: usize, opcode: Opcode, elapsed: Duration, next_pc: usize) {
+    *self.pc_counts.entry(pc).or_default() += 1;
+    *self.opcode_counts.entry(opcode).or_default() += 1;
+    *self.opcode_time.entry(opcode).or_default() += elapsed;
+    *self.stack_counts.entry(self.frames.clone()).or_default() += 1;
+    match opcode {
+      Opcode::CALL => self.frames.push(next_pc),
+      Opcode::RET if self.frames.len() > 1 => {
+        self.frames.pop();
+      }
+      _ => {}
+    }
+  }
+
+  pub fn total(&self) -> u64 {
+    self.pc_counts.values().sum()
+  }
+
+  /// Name of `pc` relative to the nearest code label at or before it, like
+  /// `loop+8`.
+  fn location(&self, pc: usize, symbols: &SymbolTable) -> String {
+    let label = symbols
+      .labels()
+      .map(|s| (s.offset() as usize, s.name()))
+      .filter(|(offset, _)| (self.code_start..=pc).contains(offset))
+      .max_by_key(|(offset, _)| *offset);
+    match label {
+      Some((offset, name)) if offset == pc => name.to_string(),
+      Some((offset, name)) => format!("{}+{}", name, pc - offset),
+      None => format!("{:04}", pc),
+    }
+  }
+
+  /// Executions attributed to the nearest code label before each address.
+  pub fn label_counts(&self, symbols: &SymbolTable) -> BTreeMap<String, u64> {
+    let mut counts = BTreeMap::new();
+    for (pc, count) in &self.pc_counts {
+      let location = self.location(*pc, symbols);
+      let label = location.split('+').next().unwrap_or_default().to_string();
+      *counts.entry(label).or_default() += count;
+    }
+    counts
+  }
+
+  /// Human readable report, hottest entries first.
+  pub fn report(&self, symbols: &SymbolTable) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "Instructions executed: {}", self.total());
+
+    let _ = writeln!(report, "By opcode:");
+    let mut opcodes: Vec<(&Opcode, &u64)> = self.opcode_counts.iter().collect();
+    opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.mnemonic().cmp(b.0.mnemonic())));
+    for (opcode, count) in opcodes {
+      let time = self.opcode_time.get(opcode).copied().unwrap_or_default();
+      let _ = writeln!(
+        report,
+        "  {:>10} {:>12?} {}",
+        count,
+        time,
+        opcode.mnemonic()
+      );
+    }
+
+    let _ = writeln!(report, "By label:");
+    for (label, count) in sorted(self.label_counts(symbols)) {
+      let _ = writeln!(report, "  {:>10} {}", count, label);
+    }
+
+    let _ = writeln!(report, "By address:");
+    let locations = self
+      .pc_counts
+      .iter()
+      .map(|(pc, count)| (format!("{:04} {}", pc, self.location(*pc, symbols)), *count));
+    for (location, count) in sorted(locations) {
+      let _ = writeln!(report, "  {:>10} {}", count, location);
+    }
+    report
+  }
+
+  /// Call stacks in the folded format read by flamegraph tools, one
+  /// `outer;inner count` line per stack.
+  pub fn folded_stacks(&self, symbols: &SymbolTable) -> String {
+    let stacks = self.stack_counts.iter().map(|(frames, count)| {
+      let names: Vec<String> = frames
+        .iter()
+        .map(|frame| self.location(*frame, symbols))
+        .collect();
+      (names.join(";"), *count)
+    });
+    let mut folded = String::new();
+    for (stack, count) in sorted(stacks) {
+      let _ = writeln!(folded, "{} {}", stack, count);
+    }
+    folded
+  }
+}
+
+/// Sorts by descending count, then by name.
+fn sorted(entries: impl IntoIterator<Item = (String, u64)>) -> Vec<(String, u64)> {
+  let mut entries: Vec<(String, u64)> = entries.into_iter().collect();
+  entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+  entries
+}
@@ -0,0 +1,301 @@
+//! VM snapshots, to checkpoint a run and resume it later.
+//!
+//! The binary format starts with the magic bytes `IRSN`, a little-endian u16
+//! version and a reserved u16, followed by the registers, the float registers
+//! (as the bits of each f64), the pc (u64), the division remainder (u32) and
+//! the flags (u8, as packed by `Flags::bits`). The program, heap, read-only
+//! data and stack come last, each prefixed by its u32 length in elements. All
+//! fields are little-endian.
+
+use super::Flags;
+use crate::instruction::REGISTER_COUNT;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"IRSN";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+  Truncated,
+  BadMagic,
+  UnsupportedVersion { version: u16 },
+  InvalidJson { message: String },
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+      SnapshotError::BadMagic => write!(f, "missing snapshot magic bytes"),
+      SnapshotError::UnsupportedVersion { version } => {
+        write!(f, "unsupported snapshot version {}", version)
+      }
+      SnapshotError::InvalidJson { message } => write!(f, "invalid JSON snapshot: {}", message),
+    }
+  }
+}
+
+impl Error for SnapshotError {}
+
+/// Complete execution state of a VM, without its host configuration
+/// (output, input, host functions, breakpoints and limits).
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub version: u16,
+  pub registers: [i32; REGISTER_COUNT],
+  #[serde(with = "float_registers")]
+  pub float_registers: [f64; REGISTER_COUNT],
+  pub pc: usize,
+  pub reminder: u32,
+  pub flags: Flags,
+  pub program: Vec<u8>,
+  pub heap: Vec<u8>,
+  pub ro_data: Vec<u8>,
+  pub stack: Vec<i32>,
+}
+
+impl Snapshot {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = SNAPSHOT_MAGIC.to_vec();
+    let mut buffer = [0; 8];
+    LittleEndian::write_u16(&mut buffer, SNAPSHOT_VERSION);
+    bytes.extend_from_slice(&buffer[..2]);
+    bytes.extend_from_slice(&[0, 0]);
+    for register in &self.registers {
+      LittleEndian::write_i32(&mut buffer, *register);
+      bytes.extend_from_slice(&buffer[..4]);
+    }
+    for register in &self.float_registers {
+      LittleEndian::write_u64(&mut buffer, register.to_bits());
+      bytes.extend_from_slice(&buffer);
+    }
+    LittleEndian::write_u64(&mut buffer, self.pc as u64);
+    bytes.extend_from_slice(&buffer);
+    LittleEndian::write_u32(&mut buffer, self.reminder);
+    bytes.extend_from_slice(&buffer[..4]);
+    bytes.push(self.flags.bits());
+    for section in [&self.program, &self.heap, &self.ro_data] {
+      LittleEndian::write_u32(&mut buffer, section.len() as u32);
+      bytes.extend_from_slice(&buffer[..4]);
+      bytes.extend_from_slice(section);
+    }
+    LittleEndian::write_u32(&mut buffer, self.stack.len() as u32);
+    bytes.extend_from_slice(&buffer[..4]);
+    for value in &self.stack {
+      LittleEndian::write_i32(&mut buffer, *value);
+      bytes.extend_from_slice(&buffer[..4]);
+    }
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+    let mut reader = Reader { bytes };
+    if reader.take(4)? != SNAPSHOT_MAGIC {
+      return Err(SnapshotError::BadMagic);
+    }
+    let version = LittleEndian::read_u16(reader.take(2)?);
+    if version != SNAPSHOT_VERSION {
+      return Err(SnapshotError::UnsupportedVersion { version });
+    }
+    reader.take(2)?;
+    let mut registers = [0; REGISTER_COUNT];
+    for register in registers.iter_mut() {
+      *register = LittleEndian::read_i32(reader.take(4)?);
+    }
+    let mut float_registers = [0.0; REGISTER_COUNT];
+    for register in float_registers.iter_mut() {
+      *register = f64::from_bits(LittleEndian::read_u64(reader.take(8)?));
+    }
+    let pc = LittleEndian::read_u64(reader.take(8)?) as usize;
+    let reminder = LittleEndian::read_u32(reader.take(4)?);
+    let flags = Flags::from_bits(reader.take(1)?[0]);
+    let program = reader.section()?.to_vec();
+    let heap = reader.section()?.to_vec();
+    let ro_data = reader.section()?.to_vec();
+    let length = LittleEndian::read_u32(reader.take(4)?) as usize;
+    let stack = reader
+      .take(length.checked_mul(4).ok_or(SnapshotError::Truncated)?)?
+      .chunks(4)
+      .map(LittleEndian::read_i32)
+      .collect();
+    Ok(Snapshot {
+      version,
+      registers,
+      float_registers,
+      pc,
+      reminder,
+      flags,
+      program,
+      heap,
+      ro_data,
+      stack,
+    })
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("snapshots always serialize")
+  }
+
+  pub fn from_json(json: &str) -> Result<Snapshot, SnapshotError> {
+    let snapshot: Snapshot =
+      serde_json::from_str(json).map_err(|e| SnapshotError::InvalidJson {
+        message: e.to_string(),
+      })?;
+    if snapshot.version != SNAPSHOT_VERSION {
+      return Err(SnapshotError::UnsupportedVersion {
+        version: snapshot.version,
+      });
+    }
+    Ok(snapshot)
+  }
+
+  /// Reads a snapshot in either format, telling them apart by the magic
+  /// bytes.
+  pub fn decode(data: &[u8]) -> Result<Snapshot, SnapshotError> {
+    if data.starts_with(&SNAPSHOT_MAGIC) {
+      return Snapshot::from_bytes(data);
+    }
+    match std::str::from_utf8(data) {
+      Ok(json) => Snapshot::from_json(json),
+      Err(_) => Err(SnapshotError::BadMagic),
+    }
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+    if self.bytes.len() < length {
+      return Err(SnapshotError::Truncated);
+    }
+    let (taken, rest) = self.bytes.split_at(length);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn section(&mut self) -> Result<&'a [u8], SnapshotError> {
+    let length = LittleEndian::read_u32(self.take(4)?) as usize;
+    self.take(length)
+  }
+}
+
+//------------------------------------------------------------------------------
+
+/// JSON has no NaN or infinities, so float registers holding them are
+/// written as the strings `NaN`, `inf` and `-inf`.
+mod float_registers {
+  use crate::instruction::REGISTER_COUNT;
+  use serde::de::Error;
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+  #[derive(Serialize, Deserialize)]
+  #[serde(untagged)]
+  enum FloatValue {
+    Number(f64),
+    Text(String),
+  }
+
+  pub fn serialize<S: Serializer>(
+    registers: &[f64; REGISTER_COUNT],
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(registers.iter().map(|value| {
+      if value.is_finite() {
+        FloatValue::Number(*value)
+      } else {
+        FloatValue::Text(value.to_string())
+      }
+    }))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<[f64; REGISTER_COUNT], D::Error> {
+    let values = Vec::<FloatValue>::deserialize(deserializer)?;
+    if values.len() != REGISTER_COUNT {
+      return Err(D::Error::invalid_length(
+        values.len(),
+        &"32 float registers",
+      ));
+    }
+    let mut registers = [0.0; REGISTER_COUNT];
+    for (register, value) in registers.iter_mut().zip(values) {
+      *register = match value {
+        FloatValue::Number(number) => number,
+        FloatValue::Text(text) => text
+          .parse()
+          .map_err(|_| D::Error::custom(format!("invalid float register {}", text)))?,
+      };
+    }
+    Ok(registers)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_snapshot() -> Snapshot {
+    let mut registers = [0; REGISTER_COUNT];
+    registers[3] = -7;
+    let mut float_registers = [0.0; REGISTER_COUNT];
+    float_registers[1] = 0.25;
+    float_registers[2] = f64::NEG_INFINITY;
+    Snapshot {
+      version: SNAPSHOT_VERSION,
+      registers,
+      float_registers,
+      pc: 72,
+      reminder: 2,
+      flags: Flags::from_bits(0b0101),
+      program: vec![1, 2, 3],
+      heap: vec![9; 5],
+      ro_data: b"hi\0".to_vec(),
+      stack: vec![-1, 68],
+    }
+  }
+
+  #[test]
+  fn test_binary_round_trip() {
+    let snapshot = test_snapshot();
+    let bytes = snapshot.to_bytes();
+    assert_eq!(&bytes[0..6], b"IRSN\x01\x00");
+    assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+    assert_eq!(Snapshot::decode(&bytes), Ok(snapshot));
+  }
+
+  #[test]
+  fn test_json_round_trip() {
+    let snapshot = test_snapshot();
+    let json = snapshot.to_json();
+    assert!(json.contains("\"carry\": true"));
+    assert!(json.contains("\"-inf\""));
+    assert_eq!(Snapshot::decode(json.as_bytes()), Ok(snapshot));
+  }
+
+  #[test]
+  fn test_reject_bad_snapshots() {
+    let mut bytes = test_snapshot().to_bytes();
+    assert_eq!(
+      Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+      Err(SnapshotError::Truncated)
+    );
+    bytes[4] = 9;
+    assert_eq!(
+      Snapshot::from_bytes(&bytes),
+      Err(SnapshotError::UnsupportedVersion { version: 9 })
+    );
+    bytes[0] = 0;
+    assert_eq!(Snapshot::from_bytes(&bytes), Err(SnapshotError::BadMagic));
+    assert!(matches!(
+      Snapshot::decode(b"{}"),
+      Err(SnapshotError::InvalidJson { .. })
+    ));
+  }
+}
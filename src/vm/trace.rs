@@ -0,0 +1,121 @@
+//! Execution tracing. A tracer installed with `VM::set_tracer` records every
+//! executed instruction matching its filter, logging it at trace level and
+//! optionally writing it as a JSON line.
+
+use super::Flags;
+use crate::instruction::Opcode;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// Selects the instructions to trace. Empty criteria match everything.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+  pub pc_range: Option<Range<usize>>,
+  pub opcodes: Option<HashSet<Opcode>>,
+}
+
+impl TraceFilter {
+  pub fn matches(&self, pc: usize, opcode: Opcode) -> bool {
+    self
+      .pc_range
+      .as_ref()
+      .is_none_or(|range| range.contains(&pc))
+      && self
+        .opcodes
+        .as_ref()
+        .is_none_or(|opcodes| opcodes.contains(&opcode))
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct RegisterChange {
+  pub register: usize,
+  pub value: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct FloatRegisterChange {
+  pub register: usize,
+  pub value: f64,
+}
+
+/// One executed instruction and its effect on the registers and flags.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct TraceEvent {
+  pub pc: usize,
+  pub instruction: String,
+  pub registers: Vec<RegisterChange>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub float_registers: Vec<FloatRegisterChange>,
+  pub flags: Flags,
+  /// The trap raised by the instruction, if it failed.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl fmt::Display for TraceEvent {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:04}: {}", self.pc, self.instruction)?;
+    for change in &self.registers {
+      write!(f, " ${}={}", change.register, change.value)?;
+    }
+    for change in &self.float_registers {
+      write!(f, " $f{}={:?}", change.register, change.value)?;
+    }
+    write!(f, " flags={}", self.flags)?;
+    if let Some(error) = &self.error {
+      write!(f, " error: {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+pub struct Tracer {
+  filter: TraceFilter,
+  sink: Option<Box<dyn Write>>,
+}
+
+impl fmt::Debug for Tracer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Tracer({:?})", self.filter)
+  }
+}
+
+impl Tracer {
+  /// Tracer emitting through the `log` crate only.
+  pub fn new(filter: TraceFilter) -> Tracer {
+    Tracer { filter, sink: None }
+  }
+
+  /// Tracer that also writes every event to `sink` as a JSON line.
+  pub fn with_sink(filter: TraceFilter, sink: Box<dyn Write>) -> Tracer {
+    Tracer {
+      filter,
+      sink: Some(sink),
+    }
+  }
+
+  pub fn filter(&self) -> &TraceFilter {
+    &self.filter
+  }
+
+  pub(super) fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+    log::trace!("{}", event);
+    if let Some(sink) = &mut self.sink {
+      serde_json::to_writer(&mut *sink, event)?;
+      sink.write_all(b"\n")?;
+    }
+    Ok(())
+  }
+
+  /// Flushes the JSON lines written so far.
+  pub fn flush(&mut self) -> io::Result<()> {
+    match &mut self.sink {
+      Some(sink) => sink.flush(),
+      None => Ok(()),
+    }
+  }
+}
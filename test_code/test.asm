@@ -3,6 +3,6 @@ ld $1 #3
 ld $2 #100
 loop: add $0 $1 $1
 gt $1 $2
-jeq @end
+jgt @end
 jmp @loop
 end: hlt
\ No newline at end of file
@@ -0,0 +1,41 @@
+use iridium_vm::{disassemble, Assembler, AssemblerErrorKind, ExitReason, VmError, VM};
+
+fn run(source: &str) -> (VM, Result<ExitReason, VmError>) {
+  let mut vm = VM::new();
+  vm.program = Assembler::new().assemble(source).unwrap();
+  let result = vm.run();
+  (vm, result)
+}
+
+#[test]
+fn test_run_sample_loop() {
+  let (vm, result) = run(include_str!("../test_code/test.asm"));
+  assert_eq!(result, Ok(ExitReason::Halted));
+  assert_eq!(vm.get_registers()[1], 101);
+}
+
+#[test]
+fn test_run_to_end_of_program() {
+  let (vm, result) = run(include_str!("../test_code/test2.asm"));
+  assert_eq!(result, Ok(ExitReason::EndOfProgram));
+  assert_eq!(vm.get_registers()[0], 10);
+}
+
+#[test]
+fn test_assembler_errors_are_returned() {
+  let errors = Assembler::new()
+    .assemble("ld $0\njmp @nowhere")
+    .unwrap_err();
+  assert_eq!(errors.len(), 2);
+  assert_eq!(errors[0].kind, AssemblerErrorKind::BadOperandCount);
+  assert_eq!(errors[1].kind, AssemblerErrorKind::UnknownSymbol);
+}
+
+#[test]
+fn test_disassemble_assembled_image() {
+  let image = Assembler::new()
+    .assemble(include_str!("../test_code/test_string.asm"))
+    .unwrap();
+  let text = disassemble(&image).unwrap();
+  assert!(text.contains(".asciiz 'Hello world!'"));
+}